@@ -0,0 +1,145 @@
+extern crate pmemkv;
+extern crate pmemkv_bench;
+
+use pmemkv::concurrent::ConcurrentEngine;
+use pmemkv::kvengine::KVEngine;
+use pmemkv::store::KvStore;
+use pmemkv_bench::ycsb::{self, CoreWorkload, Phase};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::process;
+use std::sync::Mutex;
+
+const USAGE: &str = "\
+Usage: pmemkv-ycsb [load|run|both] [OPTIONS]
+
+Runs the load phase, the run phase, or both (the default) of a YCSB core workload
+and prints the results in YCSB's format.
+
+Options:
+  -engine NAME       engine to open (default: vsmap)
+  -config JSON       engine configuration (default: {})
+  -workload NAME     standard workload a to f to start from (default: a)
+  -P FILE            YCSB workload file applied on top of it
+  -p NAME=VALUE      property overriding the workload, e.g. recordcount=100000
+  -threads N         client threads (default: 1)
+  -seed N            seed of the random choices (default: 301)
+  -h, -help          show this help
+
+Client threads use cmap and vcmap concurrently. Other engines do not support
+concurrent access, so the threads share them through a lock.";
+
+struct Options {
+    phases: Vec<Phase>,
+    engine: String,
+    config: String,
+    workload: CoreWorkload,
+    threads: usize,
+    seed: u64,
+}
+
+fn parse_args() -> Result<Options, Box<dyn Error>> {
+    let mut args = env::args().skip(1).peekable();
+    let phases = match args.peek().map(String::as_str) {
+        Some("load") => vec![Phase::Load],
+        Some("run") => vec![Phase::Run],
+        Some("both") => vec![Phase::Load, Phase::Run],
+        _ => Vec::new(),
+    };
+    let mut options = Options {
+        phases: if phases.is_empty() {
+            vec![Phase::Load, Phase::Run]
+        } else {
+            args.next();
+            phases
+        },
+        engine: "vsmap".to_string(),
+        config: "{}".to_string(),
+        workload: CoreWorkload::preset("a").unwrap(),
+        threads: 1,
+        seed: 301,
+    };
+    let mut files = Vec::new();
+    let mut properties = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("{} requires a value", arg))
+        };
+        match arg.as_str() {
+            "-engine" => options.engine = value()?,
+            "-config" => options.config = value()?,
+            "-workload" => {
+                let name = value()?;
+                options.workload = CoreWorkload::preset(&name)
+                    .ok_or_else(|| format!("unknown workload {}, expected a to f", name))?;
+            }
+            "-P" => files.push(value()?),
+            "-p" => properties.push(value()?),
+            "-threads" => options.threads = value()?.parse()?,
+            "-seed" => options.seed = value()?.parse()?,
+            "-h" | "-help" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE).into()),
+        }
+    }
+    for file in files {
+        let text = fs::read_to_string(&file).map_err(|e| format!("{}: {}", file, e))?;
+        options.workload.load_properties(&text)?;
+    }
+    for property in properties {
+        options.workload.load_properties(&property)?;
+    }
+    Ok(options)
+}
+
+fn start(engine: &str, config: &str) -> pmemkv::errors::Result<Box<dyn KvStore>> {
+    let none = None::<fn(&str, &str, &str)>;
+    if ConcurrentEngine::supports(engine) {
+        Ok(Box::new(ConcurrentEngine::start_string(
+            engine, config, none,
+        )?))
+    } else {
+        Ok(Box::new(Mutex::new(KVEngine::start_string(
+            engine, config, none,
+        )?)))
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let options = parse_args()?;
+    let kv = start(&options.engine, &options.config)
+        .map_err(|e| format!("cannot start engine {}: {}", options.engine, e))?;
+    let stdout = io::stdout();
+    for &phase in &options.phases {
+        eprintln!(
+            "{} {} with {} threads",
+            match phase {
+                Phase::Load => "Loading",
+                Phase::Run => "Running",
+            },
+            options.engine,
+            options.threads
+        );
+        let results = ycsb::run(
+            &*kv,
+            &options.workload,
+            phase,
+            options.threads,
+            options.seed,
+        );
+        results.write_to(&mut stdout.lock())?;
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
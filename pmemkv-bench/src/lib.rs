@@ -0,0 +1,3 @@
+extern crate pmemkv;
+
+pub mod ycsb;
@@ -0,0 +1,219 @@
+extern crate pmemkv;
+
+mod workload;
+
+use crate::workload::{Engine, Report, Settings, Workload, WORKLOADS};
+use serde_json::json;
+use std::env;
+use std::error::Error;
+use std::process;
+
+type BenchResult<T> = Result<T, Box<dyn Error>>;
+
+const USAGE: &str = "\
+Usage: pmemkv-bench [--NAME=VALUE...]
+
+Options:
+  --engine=NAME          engine to open (default: vsmap)
+  --config=JSON          engine configuration (default: {})
+  --benchmarks=LIST      comma-separated workloads to run in order
+                         (default: fillseq,readrandom)
+  --num=N                keys written by fills and removes (default: 1000000)
+  --reads=N              reads done by readrandom and readmissing (default: num)
+  --scans=N              scans done by scan (default: 1000)
+  --scan_length=N        pairs read by each scan (default: 100)
+  --counts=N             calls made by count (default: 100)
+  --threads=N            threads sharing each workload (default: 1)
+  --key_size=N           key size in bytes (default: 16)
+  --value_size=N         value size in bytes (default: 100)
+  --seed=N               seed of the random key choice (default: 301)
+  --json                 print the results as JSON instead of text
+  --help                 show this help
+
+Workloads: fillseq, fillrandom, overwrite, readrandom, readmissing, deleterandom,
+scan, count. Engines other than cmap and vcmap do not support concurrent access, so
+their operations on several threads are serialized by a lock.";
+
+// Latency percentiles reported, as quantiles and their names.
+const PERCENTILES: &[(f64, &str)] = &[
+    (0.5, "p50"),
+    (0.75, "p75"),
+    (0.9, "p90"),
+    (0.99, "p99"),
+    (0.999, "p99.9"),
+    (0.9999, "p99.99"),
+];
+
+struct Options {
+    engine: String,
+    config: String,
+    benchmarks: Vec<Workload>,
+    settings: Settings,
+    json: bool,
+}
+
+fn parse_args() -> BenchResult<Options> {
+    let mut options = Options {
+        engine: "vsmap".to_string(),
+        config: "{}".to_string(),
+        benchmarks: vec![Workload::FillSeq, Workload::ReadRandom],
+        settings: Settings {
+            num: 1_000_000,
+            reads: 0,
+            scans: 1000,
+            scan_length: 100,
+            counts: 100,
+            threads: 1,
+            key_size: 16,
+            value_size: 100,
+            seed: 301,
+        },
+        json: false,
+    };
+    let mut reads = None;
+    for arg in env::args().skip(1) {
+        let (name, value) = match arg.find('=') {
+            Some(i) => (&arg[..i], &arg[i + 1..]),
+            None => (arg.as_str(), ""),
+        };
+        let settings = &mut options.settings;
+        match name {
+            "--engine" => options.engine = value.to_string(),
+            "--config" => options.config = value.to_string(),
+            "--benchmarks" => {
+                options.benchmarks = value
+                    .split(',')
+                    .filter(|name| !name.is_empty())
+                    .map(|name| {
+                        Workload::parse(name).ok_or_else(|| {
+                            format!(
+                                "unknown benchmark {}, expected one of {:?}",
+                                name, WORKLOADS
+                            )
+                        })
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "--num" => settings.num = value.parse()?,
+            "--reads" => reads = Some(value.parse()?),
+            "--scans" => settings.scans = value.parse()?,
+            "--scan_length" => settings.scan_length = value.parse()?,
+            "--counts" => settings.counts = value.parse()?,
+            "--threads" => settings.threads = value.parse()?,
+            "--key_size" => settings.key_size = value.parse()?,
+            "--value_size" => settings.value_size = value.parse()?,
+            "--seed" => settings.seed = value.parse()?,
+            "--json" => options.json = true,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE).into()),
+        }
+    }
+    options.settings.reads = reads.unwrap_or(options.settings.num);
+    Ok(options)
+}
+
+fn print_header(options: &Options) {
+    let settings = &options.settings;
+    println!("Engine:     {} {}", options.engine, options.config);
+    println!("Keys:       {} bytes each", settings.key_size);
+    println!("Values:     {} bytes each", settings.value_size);
+    println!("Entries:    {}", settings.num);
+    println!("Threads:    {}", settings.threads);
+    println!("------------------------------------------------");
+}
+
+fn print_report(report: &Report) {
+    let micros_per_op = match report.ops {
+        0 => 0.0,
+        ops => report.elapsed.as_secs_f64() * 1e6 * report.threads as f64 / ops as f64,
+    };
+    let mut line = format!(
+        "{:<12} : {:11.3} micros/op {:9.0} ops/sec;",
+        report.workload.name(),
+        micros_per_op,
+        report.ops_per_sec()
+    );
+    if report.bytes > 0 {
+        line.push_str(&format!(" {:6.1} MB/s", report.mb_per_sec()));
+    }
+    match report.workload {
+        Workload::ReadRandom | Workload::ReadMissing | Workload::DeleteRandom => {
+            line.push_str(&format!(" ({} of {} found)", report.found, report.ops))
+        }
+        _ => {}
+    }
+    println!("{}", line);
+    let percentiles: Vec<_> = PERCENTILES
+        .iter()
+        .map(|&(q, name)| format!("{}: {:.2}", name, report.micros_at(q)))
+        .collect();
+    println!(
+        "Latency (micros): avg: {:.2} {} max: {:.2}",
+        report.latency.mean() / 1000.0,
+        percentiles.join(" "),
+        report.latency.max() as f64 / 1000.0
+    );
+}
+
+fn report_json(report: &Report) -> serde_json::Value {
+    let mut latency = serde_json::Map::new();
+    latency.insert("min".into(), json!(report.latency.min() as f64 / 1000.0));
+    latency.insert("avg".into(), json!(report.latency.mean() / 1000.0));
+    for &(q, name) in PERCENTILES {
+        latency.insert(name.into(), json!(report.micros_at(q)));
+    }
+    latency.insert("max".into(), json!(report.latency.max() as f64 / 1000.0));
+    json!({
+        "benchmark": report.workload.name(),
+        "threads": report.threads,
+        "ops": report.ops,
+        "found": report.found,
+        "bytes": report.bytes,
+        "seconds": report.elapsed.as_secs_f64(),
+        "ops_per_sec": report.ops_per_sec(),
+        "mb_per_sec": report.mb_per_sec(),
+        "latency_us": latency,
+    })
+}
+
+fn run() -> BenchResult<()> {
+    let options = parse_args()?;
+    let engine = Engine::start(&options.engine, &options.config)
+        .map_err(|e| format!("cannot start engine {}: {}", options.engine, e))?;
+    if !options.json {
+        print_header(&options);
+    }
+    let mut results = Vec::new();
+    for &workload in &options.benchmarks {
+        let report = workload::run(&engine, workload, &options.settings);
+        if options.json {
+            results.push(report_json(&report));
+        } else {
+            print_report(&report);
+        }
+    }
+    if options.json {
+        let settings = &options.settings;
+        let doc = json!({
+            "engine": options.engine,
+            "config": options.config,
+            "num": settings.num,
+            "threads": settings.threads,
+            "key_size": settings.key_size,
+            "value_size": settings.value_size,
+            "results": results,
+        });
+        println!("{}", serde_json::to_string_pretty(&doc)?);
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,344 @@
+use hdrhistogram::Histogram;
+use pmemkv::concurrent::ConcurrentEngine;
+use pmemkv::errors::Result;
+use pmemkv::kvengine::KVEngine;
+use std::cell::Cell;
+use std::fmt::Write;
+use std::ops::ControlFlow;
+use std::os::raw::c_char;
+use std::sync::{Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Latencies are recorded in nanoseconds, up to an hour, with 3 significant digits.
+const MAX_LATENCY_NANOS: u64 = 3_600_000_000_000;
+const VALUE_BUFFER_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Workload {
+    /// Writes `num` keys in order.
+    FillSeq,
+    /// Writes `num` keys in random order.
+    FillRandom,
+    /// Like `FillRandom`, meant to run over a filled engine.
+    Overwrite,
+    /// Reads `reads` random keys out of the `num` written by a fill.
+    ReadRandom,
+    /// Reads `reads` random keys that were never written.
+    ReadMissing,
+    /// Removes `num` random keys.
+    DeleteRandom,
+    /// Reads `scan_length` pairs after each of `scans` random keys.
+    Scan,
+    /// Calls `count` `counts` times.
+    Count,
+}
+
+pub const WORKLOADS: &[&str] = &[
+    "fillseq",
+    "fillrandom",
+    "overwrite",
+    "readrandom",
+    "readmissing",
+    "deleterandom",
+    "scan",
+    "count",
+];
+
+impl Workload {
+    pub fn parse(name: &str) -> Option<Workload> {
+        match name {
+            "fillseq" => Some(Workload::FillSeq),
+            "fillrandom" => Some(Workload::FillRandom),
+            "overwrite" => Some(Workload::Overwrite),
+            "readrandom" => Some(Workload::ReadRandom),
+            "readmissing" => Some(Workload::ReadMissing),
+            "deleterandom" => Some(Workload::DeleteRandom),
+            "scan" => Some(Workload::Scan),
+            "count" => Some(Workload::Count),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Workload::FillSeq => "fillseq",
+            Workload::FillRandom => "fillrandom",
+            Workload::Overwrite => "overwrite",
+            Workload::ReadRandom => "readrandom",
+            Workload::ReadMissing => "readmissing",
+            Workload::DeleteRandom => "deleterandom",
+            Workload::Scan => "scan",
+            Workload::Count => "count",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub num: u64,
+    pub reads: u64,
+    pub scans: u64,
+    pub scan_length: usize,
+    pub counts: u64,
+    pub threads: usize,
+    pub key_size: usize,
+    pub value_size: usize,
+    pub seed: u64,
+}
+
+impl Settings {
+    fn ops(&self, workload: Workload) -> u64 {
+        match workload {
+            Workload::FillSeq
+            | Workload::FillRandom
+            | Workload::Overwrite
+            | Workload::DeleteRandom => self.num,
+            Workload::ReadRandom | Workload::ReadMissing => self.reads,
+            Workload::Scan => self.scans,
+            Workload::Count => self.counts,
+        }
+    }
+}
+
+/// Outcome of one workload, summed over its threads.
+pub struct Report {
+    pub workload: Workload,
+    pub threads: usize,
+    pub ops: u64,
+    /// Keys found by reads and removes, pairs visited by scans, or pairs counted.
+    pub found: u64,
+    /// Key and value bytes written or read.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Per-operation latency in nanoseconds.
+    pub latency: Histogram<u64>,
+}
+
+impl Report {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / 1_048_576.0 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    /// Latency at `quantile` (0.0 to 1.0) in microseconds.
+    pub fn micros_at(&self, quantile: f64) -> f64 {
+        self.latency.value_at_quantile(quantile) as f64 / 1000.0
+    }
+}
+
+// xorshift64*: fast and good enough to pick keys; each thread has its own.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn uniform(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+}
+
+// Values are consecutive slices of a buffer of random letters, as in db_bench, so
+// generating them costs next to nothing.
+struct Values {
+    data: String,
+    pos: usize,
+}
+
+impl Values {
+    fn new(rnd: &mut Random, size: usize) -> Values {
+        let data = (0..VALUE_BUFFER_BYTES.max(size))
+            .map(|_| char::from(b'a' + rnd.uniform(26) as u8))
+            .collect();
+        Values { data, pos: 0 }
+    }
+
+    fn next(&mut self, size: usize) -> &str {
+        if self.pos + size > self.data.len() {
+            self.pos = 0;
+        }
+        self.pos += size;
+        &self.data[self.pos - size..self.pos]
+    }
+}
+
+fn format_key(buf: &mut String, n: u64, size: usize) {
+    buf.clear();
+    let _ = write!(buf, "{:0width$}", n, width = size);
+}
+
+#[derive(Default)]
+struct Counters {
+    ops: u64,
+    found: u64,
+    bytes: u64,
+}
+
+/// Engine shared by the benchmark threads. Engines that support concurrent access are
+/// used as they are; any other one is serialized by a lock.
+pub enum Engine {
+    Concurrent(ConcurrentEngine),
+    Exclusive(Mutex<KVEngine>),
+}
+
+impl Engine {
+    pub fn start(engine: &str, config: &str) -> Result<Engine> {
+        let none = None::<fn(&str, &str, &str)>;
+        if ConcurrentEngine::supports(engine) {
+            ConcurrentEngine::start_string(engine, config, none).map(Engine::Concurrent)
+        } else {
+            KVEngine::start_string(engine, config, none).map(|kv| Engine::Exclusive(Mutex::new(kv)))
+        }
+    }
+
+    fn read<T, F: FnOnce(&KVEngine) -> T>(&self, f: F) -> T {
+        match self {
+            Engine::Concurrent(kv) => f(kv.engine()),
+            Engine::Exclusive(kv) => f(&kv.lock().unwrap_or_else(|e| e.into_inner())),
+        }
+    }
+
+    fn put(&self, key: &str, value: &str) -> Result<()> {
+        match self {
+            Engine::Concurrent(kv) => kv.put(key, value.as_bytes()),
+            Engine::Exclusive(kv) => kv.lock().unwrap_or_else(|e| e.into_inner()).put(key, value),
+        }
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        match self {
+            Engine::Concurrent(kv) => kv.remove(key),
+            Engine::Exclusive(kv) => kv.lock().unwrap_or_else(|e| e.into_inner()).remove(key),
+        }
+    }
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_NANOS, 3).unwrap()
+}
+
+// Runs operations `begin..end` of the workload once every thread is set up, and
+// returns how long they took.
+fn run_thread(
+    engine: &Engine,
+    workload: Workload,
+    settings: &Settings,
+    thread: usize,
+    (begin, end): (u64, u64),
+    ready: &Barrier,
+) -> (Counters, Histogram<u64>, Duration) {
+    let mut rnd = Random::new(settings.seed.wrapping_add(thread as u64));
+    let mut values = Values::new(&mut rnd, settings.value_size);
+    let mut latency = new_histogram();
+    let mut counters = Counters::default();
+    let mut key = String::with_capacity(settings.key_size);
+    let num = settings.num;
+    ready.wait();
+    let run_started = Instant::now();
+    for i in begin..end {
+        let n = match workload {
+            Workload::FillSeq => i,
+            Workload::ReadMissing => num + rnd.uniform(num),
+            _ => rnd.uniform(num),
+        };
+        format_key(&mut key, n, settings.key_size);
+        let started = Instant::now();
+        match workload {
+            Workload::FillSeq | Workload::FillRandom | Workload::Overwrite => {
+                let value = values.next(settings.value_size);
+                engine.put(&key, value).expect("put failed");
+                counters.bytes += (key.len() + value.len()) as u64;
+            }
+            Workload::DeleteRandom => {
+                if engine.remove(&key).is_ok() {
+                    counters.found += 1;
+                }
+            }
+            Workload::ReadRandom | Workload::ReadMissing => {
+                let size = Cell::new(None);
+                engine
+                    .read(|kv| kv.get(&key, Some(|v: &[c_char]| size.set(Some(v.len())))))
+                    .expect("get failed");
+                if let Some(size) = size.get() {
+                    counters.found += 1;
+                    counters.bytes += (key.len() + size) as u64;
+                }
+            }
+            Workload::Scan => {
+                let mut visited = 0;
+                let _ = engine.read(|kv| {
+                    kv.each_above_until(&key, |k, v| {
+                        visited += 1;
+                        counters.bytes += (k.len() + v.len()) as u64;
+                        if visited < settings.scan_length {
+                            ControlFlow::Continue(())
+                        } else {
+                            ControlFlow::Break(())
+                        }
+                    })
+                });
+                counters.found += visited as u64;
+            }
+            Workload::Count => {
+                counters.found += engine.read(|kv| kv.count()) as u64;
+            }
+        }
+        latency.saturating_record(started.elapsed().as_nanos() as u64);
+        counters.ops += 1;
+    }
+    (counters, latency, run_started.elapsed())
+}
+
+/// Runs `workload` on `settings.threads` threads, each taking an equal share of its
+/// operations. The elapsed time is that of the slowest thread.
+pub fn run(engine: &Engine, workload: Workload, settings: &Settings) -> Report {
+    let threads = settings.threads.max(1);
+    let ops = settings.ops(workload);
+    let ready = Barrier::new(threads);
+    let ready = &ready;
+    let results: Vec<_> = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let begin = ops * t as u64 / threads as u64;
+                let end = ops * (t as u64 + 1) / threads as u64;
+                s.spawn(move || run_thread(engine, workload, settings, t, (begin, end), ready))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("benchmark thread panicked"))
+            .collect()
+    });
+    let mut report = Report {
+        workload,
+        threads,
+        ops: 0,
+        found: 0,
+        bytes: 0,
+        elapsed: Duration::default(),
+        latency: new_histogram(),
+    };
+    for (counters, latency, elapsed) in results {
+        report.elapsed = report.elapsed.max(elapsed);
+        report.ops += counters.ops;
+        report.found += counters.found;
+        report.bytes += counters.bytes;
+        report
+            .latency
+            .add(latency)
+            .expect("histograms have the same bounds");
+    }
+    report
+}
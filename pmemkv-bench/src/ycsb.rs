@@ -0,0 +1,763 @@
+//! Driver for the YCSB core workloads, running against any `KvStore`.
+//!
+//! Records are stored as one value of `fieldcount * fieldlength` bytes under the key
+//! `user<hash>`, and updates rewrite the whole record. Results are printed in the
+//! format of YCSB's text exporter so existing tooling can read them.
+
+use hdrhistogram::Histogram;
+use pmemkv::store::KvStore;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ZIPFIAN_CONSTANT: f64 = 0.99;
+// Item count and zeta of the Zipfian distribution behind `ScrambledZipfian`, as in
+// YCSB, so that keys can be scrambled without computing zeta for the record count.
+const SCRAMBLED_ITEM_COUNT: u64 = 10_000_000_000;
+const SCRAMBLED_ZETAN: f64 = 26.469_028_201_783_02;
+const VALUE_BUFFER_BYTES: usize = 1 << 20;
+const MAX_LATENCY_MICROS: u64 = 3_600_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    Uniform,
+    Zipfian,
+    /// Zipfian over the most recently inserted records.
+    Latest,
+}
+
+impl Distribution {
+    fn parse(name: &str) -> Option<Distribution> {
+        match name {
+            "uniform" => Some(Distribution::Uniform),
+            "zipfian" => Some(Distribution::Zipfian),
+            "latest" => Some(Distribution::Latest),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Inserts `recordcount` records.
+    Load,
+    /// Runs `operationcount` operations over loaded records.
+    Run,
+}
+
+/// The properties of YCSB's `CoreWorkload` this driver understands.
+#[derive(Debug, Clone)]
+pub struct CoreWorkload {
+    pub record_count: u64,
+    pub operation_count: u64,
+    pub field_count: usize,
+    pub field_length: usize,
+    pub read_proportion: f64,
+    pub update_proportion: f64,
+    pub insert_proportion: f64,
+    pub scan_proportion: f64,
+    pub read_modify_write_proportion: f64,
+    pub request_distribution: Distribution,
+    pub max_scan_length: usize,
+    pub scan_length_distribution: Distribution,
+    /// Whether keys follow insertion order instead of being hashed.
+    pub ordered_inserts: bool,
+}
+
+impl Default for CoreWorkload {
+    fn default() -> CoreWorkload {
+        CoreWorkload {
+            record_count: 1000,
+            operation_count: 1000,
+            field_count: 10,
+            field_length: 100,
+            read_proportion: 0.95,
+            update_proportion: 0.05,
+            insert_proportion: 0.0,
+            scan_proportion: 0.0,
+            read_modify_write_proportion: 0.0,
+            request_distribution: Distribution::Uniform,
+            max_scan_length: 1000,
+            scan_length_distribution: Distribution::Uniform,
+            ordered_inserts: false,
+        }
+    }
+}
+
+impl CoreWorkload {
+    /// One of the standard workloads `a` to `f`, as shipped with YCSB.
+    pub fn preset(name: &str) -> Option<CoreWorkload> {
+        let mut w = CoreWorkload {
+            read_proportion: 0.0,
+            update_proportion: 0.0,
+            request_distribution: Distribution::Zipfian,
+            ..CoreWorkload::default()
+        };
+        match name.trim_start_matches("workload") {
+            "a" => {
+                w.read_proportion = 0.5;
+                w.update_proportion = 0.5;
+            }
+            "b" => {
+                w.read_proportion = 0.95;
+                w.update_proportion = 0.05;
+            }
+            "c" => w.read_proportion = 1.0,
+            "d" => {
+                w.read_proportion = 0.95;
+                w.insert_proportion = 0.05;
+                w.request_distribution = Distribution::Latest;
+            }
+            "e" => {
+                w.scan_proportion = 0.95;
+                w.insert_proportion = 0.05;
+                w.max_scan_length = 100;
+            }
+            "f" => {
+                w.read_proportion = 0.5;
+                w.read_modify_write_proportion = 0.5;
+            }
+            _ => return None,
+        }
+        Some(w)
+    }
+
+    /// Sets a property by its YCSB name. Unknown properties are ignored, as YCSB does.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let invalid = || format!("invalid value for {}: {}", name, value);
+        let float = || value.parse::<f64>().map_err(|_| invalid());
+        let int = || value.parse::<u64>().map_err(|_| invalid());
+        let distribution = || Distribution::parse(value).ok_or_else(invalid);
+        match name.trim() {
+            "recordcount" => self.record_count = int()?,
+            "operationcount" => self.operation_count = int()?,
+            "fieldcount" => self.field_count = int()? as usize,
+            "fieldlength" => self.field_length = int()? as usize,
+            "readproportion" => self.read_proportion = float()?,
+            "updateproportion" => self.update_proportion = float()?,
+            "insertproportion" => self.insert_proportion = float()?,
+            "scanproportion" => self.scan_proportion = float()?,
+            "readmodifywriteproportion" => self.read_modify_write_proportion = float()?,
+            "requestdistribution" => self.request_distribution = distribution()?,
+            "maxscanlength" => self.max_scan_length = int()? as usize,
+            "scanlengthdistribution" => self.scan_length_distribution = distribution()?,
+            "insertorder" => self.ordered_inserts = value == "ordered",
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Applies the `name=value` lines of a YCSB workload file. Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn load_properties(&mut self, text: &str) -> Result<(), String> {
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find('=') {
+                Some(i) => self.set(&line[..i], &line[i + 1..])?,
+                None => return Err(format!("bad property line: {}", line)),
+            }
+        }
+        Ok(())
+    }
+
+    fn record_bytes(&self) -> usize {
+        self.field_count * self.field_length
+    }
+
+    fn key(&self, keynum: u64) -> String {
+        let keynum = if self.ordered_inserts {
+            keynum
+        } else {
+            fnv_hash64(keynum)
+        };
+        format!("user{}", keynum)
+    }
+}
+
+// YCSB's `Utils.fnvhash64`, so hashed keys match those of the Java client.
+fn fnv_hash64(mut val: u64) -> u64 {
+    let mut hash: i64 = 0xcbf2_9ce4_8422_2325_u64 as i64;
+    for _ in 0..8 {
+        hash ^= (val & 0xff) as i64;
+        hash = hash.wrapping_mul(1_099_511_628_211);
+        val >>= 8;
+    }
+    hash.wrapping_abs() as u64
+}
+
+// xorshift64*, one per thread.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn uniform(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+}
+
+fn zeta(from: u64, to: u64, theta: f64, initial: f64) -> f64 {
+    (from..to).fold(initial, |sum, i| sum + 1.0 / ((i + 1) as f64).powf(theta))
+}
+
+// YCSB's `ZipfianGenerator` (Gray et al., "Quickly Generating Billion-Record
+// Synthetic Databases"): item 0 is the most popular. Zeta is extended incrementally
+// when the item count grows.
+#[derive(Clone)]
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zeta2theta: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64) -> Zipfian {
+        let zetan = zeta(0, items, ZIPFIAN_CONSTANT, 0.0);
+        Zipfian::with_zetan(items, zetan)
+    }
+
+    fn with_zetan(items: u64, zetan: f64) -> Zipfian {
+        let theta = ZIPFIAN_CONSTANT;
+        let mut z = Zipfian {
+            items,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zeta2theta: zeta(0, 2, theta, 0.0),
+            zetan,
+            eta: 0.0,
+        };
+        z.eta = z.eta();
+        z
+    }
+
+    fn eta(&self) -> f64 {
+        (1.0 - (2.0 / self.items as f64).powf(1.0 - self.theta))
+            / (1.0 - self.zeta2theta / self.zetan)
+    }
+
+    fn next(&mut self, rnd: &mut Random, items: u64) -> u64 {
+        if items > self.items {
+            self.zetan = zeta(self.items, items, self.theta, self.zetan);
+            self.items = items;
+            self.eta = self.eta();
+        }
+        let u = rnd.fraction();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let n = self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (n as u64).min(self.items - 1)
+    }
+}
+
+// YCSB's `AcknowledgedCounterGenerator`: hands out the key numbers of inserts, and
+// only counts a record as present once it and every record before it have been
+// written, so reads never pick a key whose insert is still in flight.
+struct InsertCounter {
+    next: AtomicU64,
+    acknowledged: AtomicU64,
+    // Key numbers written out of order, past `acknowledged`.
+    pending: Mutex<BTreeSet<u64>>,
+}
+
+impl InsertCounter {
+    fn new(first: u64) -> InsertCounter {
+        InsertCounter {
+            next: AtomicU64::new(first),
+            acknowledged: AtomicU64::new(first),
+            pending: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    fn next(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn acknowledge(&self, keynum: u64) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.insert(keynum);
+        let mut limit = self.acknowledged.load(Ordering::SeqCst);
+        while pending.remove(&limit) {
+            limit += 1;
+        }
+        self.acknowledged.store(limit, Ordering::SeqCst);
+    }
+
+    // Number of records known to be present.
+    fn acknowledged(&self) -> u64 {
+        self.acknowledged.load(Ordering::SeqCst)
+    }
+}
+
+// Chooses the record each operation works on.
+#[derive(Clone)]
+enum KeyChooser {
+    Uniform,
+    // YCSB's `ScrambledZipfianGenerator`: popular items spread over the key space.
+    ScrambledZipfian(Zipfian, u64),
+    // YCSB's `SkewedLatestGenerator`: the most recent inserts are the most popular.
+    Latest(Zipfian),
+}
+
+impl KeyChooser {
+    fn new(workload: &CoreWorkload) -> KeyChooser {
+        match workload.request_distribution {
+            Distribution::Uniform => KeyChooser::Uniform,
+            Distribution::Zipfian => {
+                // Leave room for the records inserted while running, as YCSB does.
+                let expected_new =
+                    (workload.operation_count as f64 * workload.insert_proportion * 2.0) as u64;
+                KeyChooser::ScrambledZipfian(
+                    Zipfian::with_zetan(SCRAMBLED_ITEM_COUNT, SCRAMBLED_ZETAN),
+                    workload.record_count + expected_new,
+                )
+            }
+            Distribution::Latest => KeyChooser::Latest(Zipfian::new(workload.record_count.max(1))),
+        }
+    }
+
+    // Picks one of the `inserted` records present so far.
+    fn next(&mut self, rnd: &mut Random, inserted: u64) -> u64 {
+        let inserted = inserted.max(1);
+        match self {
+            KeyChooser::Uniform => rnd.uniform(inserted),
+            KeyChooser::ScrambledZipfian(zipfian, items) => loop {
+                let n = zipfian.next(rnd, SCRAMBLED_ITEM_COUNT);
+                let keynum = fnv_hash64(n) % (*items).max(1);
+                if keynum < inserted {
+                    return keynum;
+                }
+            },
+            KeyChooser::Latest(zipfian) => inserted - 1 - zipfian.next(rnd, inserted),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operation {
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+}
+
+const OPERATIONS: &[Operation] = &[
+    Operation::Read,
+    Operation::Update,
+    Operation::Insert,
+    Operation::Scan,
+    Operation::ReadModifyWrite,
+];
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Read => "READ",
+            Operation::Update => "UPDATE",
+            Operation::Insert => "INSERT",
+            Operation::Scan => "SCAN",
+            Operation::ReadModifyWrite => "READ-MODIFY-WRITE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    NotFound,
+    Error,
+}
+
+const STATUSES: &[Status] = &[Status::Ok, Status::NotFound, Status::Error];
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::NotFound => "NOT_FOUND",
+            Status::Error => "ERROR",
+        }
+    }
+}
+
+/// Latencies and return codes of one kind of operation.
+#[derive(Clone)]
+pub struct Measurement {
+    name: &'static str,
+    /// Latency in microseconds.
+    latency: Histogram<u64>,
+    statuses: [u64; 3],
+}
+
+impl Measurement {
+    fn new(name: &'static str) -> Measurement {
+        Measurement {
+            name,
+            latency: Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 3).unwrap(),
+            statuses: [0; 3],
+        }
+    }
+
+    fn record(&mut self, started: Instant, status: Status) {
+        self.latency
+            .saturating_record(started.elapsed().as_micros() as u64);
+        self.statuses[status as usize] += 1;
+    }
+
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    pub fn operations(&self) -> u64 {
+        self.latency.len()
+    }
+}
+
+/// Outcome of a load or run phase.
+pub struct Results {
+    pub runtime: Duration,
+    pub operations: u64,
+    measurements: Vec<Measurement>,
+}
+
+impl Results {
+    pub fn throughput(&self) -> f64 {
+        self.operations as f64 / self.runtime.as_secs_f64().max(1e-9)
+    }
+
+    /// Measurements of the operations that ran, in a fixed order.
+    pub fn measurements(&self) -> impl Iterator<Item = &Measurement> {
+        self.measurements.iter().filter(|m| m.operations() > 0)
+    }
+
+    /// Writes the results the way YCSB's text exporter does.
+    pub fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "[OVERALL], RunTime(ms), {}", self.runtime.as_millis())?;
+        writeln!(out, "[OVERALL], Throughput(ops/sec), {}", self.throughput())?;
+        for m in self.measurements() {
+            let h = &m.latency;
+            writeln!(out, "[{}], Operations, {}", m.name, h.len())?;
+            writeln!(out, "[{}], AverageLatency(us), {}", m.name, h.mean())?;
+            writeln!(out, "[{}], MinLatency(us), {}", m.name, h.min())?;
+            writeln!(out, "[{}], MaxLatency(us), {}", m.name, h.max())?;
+            for &(q, label) in &[(0.95, "95th"), (0.99, "99th")] {
+                writeln!(
+                    out,
+                    "[{}], {}PercentileLatency(us), {}",
+                    m.name,
+                    label,
+                    h.value_at_quantile(q)
+                )?;
+            }
+            for &status in STATUSES {
+                let n = m.statuses[status as usize];
+                if n > 0 {
+                    writeln!(out, "[{}], Return={}, {}", m.name, status.name(), n)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Values are consecutive slices of a buffer of random letters.
+struct Values {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Values {
+    fn new(rnd: &mut Random, size: usize) -> Values {
+        let data = (0..VALUE_BUFFER_BYTES.max(size))
+            .map(|_| b'a' + rnd.uniform(26) as u8)
+            .collect();
+        Values { data, pos: 0 }
+    }
+
+    fn next(&mut self, size: usize) -> &[u8] {
+        if self.pos + size > self.data.len() {
+            self.pos = 0;
+        }
+        self.pos += size;
+        &self.data[self.pos - size..self.pos]
+    }
+}
+
+fn status_of<T>(result: pmemkv::errors::Result<Option<T>>) -> Status {
+    match result {
+        Ok(Some(_)) => Status::Ok,
+        Ok(None) => Status::NotFound,
+        Err(_) => Status::Error,
+    }
+}
+
+fn put_status(result: pmemkv::errors::Result<()>) -> Status {
+    match result {
+        Ok(()) => Status::Ok,
+        Err(_) => Status::Error,
+    }
+}
+
+struct Client<'a, S: ?Sized> {
+    store: &'a S,
+    workload: &'a CoreWorkload,
+    rnd: Random,
+    values: Values,
+    keys: KeyChooser,
+    scan_lengths: Option<Zipfian>,
+    measurements: Vec<Measurement>,
+    // Key numbers of the inserts, shared by all clients.
+    inserts: &'a InsertCounter,
+}
+
+impl<'a, S: KvStore + ?Sized> Client<'a, S> {
+    fn measure(&mut self, operation: Operation, started: Instant, status: Status) {
+        self.measurements[operation as usize].record(started, status);
+    }
+
+    fn insert(&mut self) {
+        let keynum = self.inserts.next();
+        let key = self.workload.key(keynum);
+        let started = Instant::now();
+        let value = self.values.next(self.workload.record_bytes());
+        let status = put_status(self.store.put(&key, value));
+        self.inserts.acknowledge(keynum);
+        self.measure(Operation::Insert, started, status);
+    }
+
+    fn choose_key(&mut self) -> String {
+        let inserted = self.inserts.acknowledged();
+        let keynum = self.keys.next(&mut self.rnd, inserted);
+        self.workload.key(keynum)
+    }
+
+    fn choose_operation(&mut self) -> Operation {
+        let w = self.workload;
+        let weights = [
+            w.read_proportion,
+            w.update_proportion,
+            w.insert_proportion,
+            w.scan_proportion,
+            w.read_modify_write_proportion,
+        ];
+        let mut point = self.rnd.fraction() * weights.iter().sum::<f64>();
+        for (&operation, &weight) in OPERATIONS.iter().zip(&weights) {
+            if point < weight {
+                return operation;
+            }
+            point -= weight;
+        }
+        Operation::Read
+    }
+
+    fn transaction(&mut self) {
+        let operation = self.choose_operation();
+        if operation == Operation::Insert {
+            return self.insert();
+        }
+        let key = self.choose_key();
+        let record_bytes = self.workload.record_bytes();
+        let started = Instant::now();
+        let status = match operation {
+            Operation::Read => status_of(self.store.get(&key)),
+            Operation::Update => put_status(self.store.put(&key, self.values.next(record_bytes))),
+            Operation::Scan => {
+                let max = self.workload.max_scan_length.max(1) as u64;
+                let length = match self.scan_lengths {
+                    Some(ref mut zipfian) => zipfian.next(&mut self.rnd, max) + 1,
+                    None => self.rnd.uniform(max) + 1,
+                };
+                match self.store.scan(&key, length as usize) {
+                    Ok(_) => Status::Ok,
+                    Err(_) => Status::Error,
+                }
+            }
+            Operation::ReadModifyWrite => {
+                let read = self.store.get(&key);
+                let read_status = status_of(read);
+                self.measure(Operation::Read, started, read_status);
+                let update_started = Instant::now();
+                let status = put_status(self.store.put(&key, self.values.next(record_bytes)));
+                self.measure(Operation::Update, update_started, status);
+                match read_status {
+                    Status::Ok => status,
+                    other => other,
+                }
+            }
+            Operation::Insert => unreachable!(),
+        };
+        self.measure(operation, started, status);
+    }
+}
+
+/// Runs a phase of `workload` against `store` on `threads` client threads, which
+/// share the operations equally. The run phase expects the records of the load
+/// phase to be present.
+pub fn run<S>(
+    store: &S,
+    workload: &CoreWorkload,
+    phase: Phase,
+    threads: usize,
+    seed: u64,
+) -> Results
+where
+    S: KvStore + ?Sized,
+{
+    let threads = threads.max(1);
+    let (ops, first_insert) = match phase {
+        Phase::Load => (workload.record_count, 0),
+        Phase::Run => (workload.operation_count, workload.record_count),
+    };
+    let inserts = InsertCounter::new(first_insert);
+    let keys = KeyChooser::new(workload);
+    let scan_lengths = match workload.scan_length_distribution {
+        Distribution::Zipfian => Some(Zipfian::new(workload.max_scan_length.max(1) as u64)),
+        _ => None,
+    };
+    let ready = Barrier::new(threads + 1);
+    let started = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let count = ops * (t as u64 + 1) / threads as u64 - ops * t as u64 / threads as u64;
+                let mut rnd = Random::new(seed.wrapping_add(t as u64));
+                let values = Values::new(&mut rnd, workload.record_bytes());
+                let mut client = Client {
+                    store,
+                    workload,
+                    rnd,
+                    values,
+                    keys: keys.clone(),
+                    scan_lengths: scan_lengths.clone(),
+                    measurements: OPERATIONS
+                        .iter()
+                        .map(|o| Measurement::new(o.name()))
+                        .collect(),
+                    inserts: &inserts,
+                };
+                let ready = &ready;
+                s.spawn(move || {
+                    ready.wait();
+                    for _ in 0..count {
+                        match phase {
+                            Phase::Load => client.insert(),
+                            Phase::Run => client.transaction(),
+                        }
+                    }
+                    client.measurements
+                })
+            })
+            .collect();
+        ready.wait();
+        let started = Instant::now();
+        let measurements: Vec<_> = handles
+            .into_iter()
+            .map(|h| h.join().expect("client thread panicked"))
+            .collect();
+        (started.elapsed(), measurements)
+    });
+    let (runtime, per_thread) = started;
+    let mut measurements: Vec<_> = OPERATIONS
+        .iter()
+        .map(|o| Measurement::new(o.name()))
+        .collect();
+    for thread in per_thread {
+        for (total, m) in measurements.iter_mut().zip(thread) {
+            total
+                .latency
+                .add(&m.latency)
+                .expect("histograms have the same bounds");
+            for (a, b) in total.statuses.iter_mut().zip(m.statuses.iter()) {
+                *a += b;
+            }
+        }
+    }
+    Results {
+        runtime,
+        operations: ops,
+        measurements,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_keys_match_ycsb() {
+        assert_eq!(fnv_hash64(0), 6_284_781_860_667_377_211);
+        assert_eq!(fnv_hash64(1), 8_517_097_267_634_966_620);
+        assert_eq!(fnv_hash64(42), 55_488_592_825_689_361);
+        let workload = CoreWorkload::default();
+        assert_eq!(workload.key(0), "user6284781860667377211");
+    }
+
+    #[test]
+    fn zipfian_matches_ycsb() {
+        let zipfian = Zipfian::new(1000);
+        assert!((zipfian.zetan - 7.728_953_217_284_729).abs() < 1e-9);
+        // Item 0 is drawn with probability 1 / zetan, item 1 with 2^-theta / zetan.
+        let mut zipfian = Zipfian::new(1000);
+        let mut rnd = Random::new(7);
+        let mut hits = [0u32; 2];
+        let draws = 200_000;
+        for _ in 0..draws {
+            let n = zipfian.next(&mut rnd, 1000);
+            assert!(n < 1000);
+            if n < 2 {
+                hits[n as usize] += 1;
+            }
+        }
+        let share = |n: u32| f64::from(n) / f64::from(draws);
+        assert!((share(hits[0]) - 0.129_384).abs() < 0.005);
+        assert!((share(hits[1]) - 0.065_141).abs() < 0.005);
+    }
+
+    #[test]
+    fn zipfian_grows_incrementally() {
+        let mut grown = Zipfian::new(100);
+        grown.next(&mut Random::new(1), 1000);
+        let fresh = Zipfian::new(1000);
+        assert!((grown.zetan - fresh.zetan).abs() < 1e-9);
+        assert!((grown.eta - fresh.eta).abs() < 1e-9);
+    }
+
+    #[test]
+    fn inserts_acknowledged_in_order() {
+        let counter = InsertCounter::new(10);
+        let (a, b, c) = (counter.next(), counter.next(), counter.next());
+        assert_eq!((a, b, c), (10, 11, 12));
+        counter.acknowledge(c);
+        counter.acknowledge(b);
+        assert_eq!(counter.acknowledged(), 10);
+        counter.acknowledge(a);
+        assert_eq!(counter.acknowledged(), 13);
+    }
+}
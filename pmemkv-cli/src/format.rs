@@ -0,0 +1,40 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// How keys and values are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+pub const FORMATS: &[&str] = &["utf8", "hex", "base64"];
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "utf8" => Some(Format::Utf8),
+            "hex" => Some(Format::Hex),
+            "base64" => Some(Format::Base64),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Utf8 => "utf8",
+            Format::Hex => "hex",
+            Format::Base64 => "base64",
+        }
+    }
+
+    /// Renders `bytes`; invalid UTF-8 is replaced in `Utf8` mode.
+    pub fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Format::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Format::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            Format::Base64 => STANDARD.encode(bytes),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+extern crate pmemkv;
+
+mod format;
+mod session;
+
+use crate::format::{Format, FORMATS};
+use crate::session::{split_line, CliResult, Flow, Session, COMMANDS};
+use pmemkv::kvengine::KVEngine;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "\
+Usage: pmemkv-cli [OPTIONS] [COMMAND [ARGS...]]
+
+Runs COMMAND against the engine, or commands read from stdin, one per line, or an
+interactive REPL when stdin is a terminal.
+
+Options:
+  -e, --engine NAME    engine to open (default: vsmap)
+  -c, --config JSON    engine configuration (default: {})
+  -f, --format MODE    display mode: utf8, hex or base64 (default: utf8)
+  -h, --help           show this help
+
+Commands: put, get, del, exists, count, scan, keys, dump, restore, migrate, verify,
+format, help";
+
+struct Options {
+    engine: String,
+    config: String,
+    format: Format,
+    command: Vec<String>,
+}
+
+fn parse_args() -> CliResult<Options> {
+    let mut options = Options {
+        engine: "vsmap".to_string(),
+        config: "{}".to_string(),
+        format: Format::Utf8,
+        command: Vec::new(),
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("{} requires a value", name))
+        };
+        match arg.as_str() {
+            "-e" | "--engine" => options.engine = value(&arg)?,
+            "-c" | "--config" => options.config = value(&arg)?,
+            "-f" | "--format" => {
+                let name = value(&arg)?;
+                options.format = Format::parse(&name).ok_or_else(|| {
+                    format!("unknown format {}, expected one of {:?}", name, FORMATS)
+                })?;
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') && options.command.is_empty() => {
+                return Err(format!("unknown option {}\n\n{}", arg, USAGE).into())
+            }
+            _ => {
+                options.command.push(arg);
+                options.command.extend(args.by_ref());
+            }
+        }
+    }
+    Ok(options)
+}
+
+struct CommandHelper;
+
+impl Completer for CommandHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let (start, candidates) = match line.find(char::is_whitespace) {
+            None => (0, COMMANDS),
+            Some(_) if line.starts_with("format ") => (line.rfind(' ').unwrap_or(0) + 1, FORMATS),
+            Some(_) => return Ok((pos, Vec::new())),
+        };
+        let word = &line[start..];
+        let pairs = candidates
+            .iter()
+            .filter(|c| c.starts_with(word))
+            .map(|c| Pair {
+                display: c.to_string(),
+                replacement: c.to_string(),
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".pmemkv_cli_history"))
+}
+
+fn repl(session: &mut Session) -> CliResult<()> {
+    let mut editor: Editor<CommandHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(CommandHelper));
+    let history = history_path();
+    if let Some(ref path) = history {
+        let _ = editor.load_history(path);
+    }
+    let stdout = io::stdout();
+    loop {
+        let line = match editor.readline("pmemkv> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        let result =
+            split_line(&line).and_then(|words| session.execute(&words, &mut stdout.lock()));
+        match result {
+            Ok(Flow::Quit) => break,
+            Ok(Flow::Continue) => {}
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+    if let Some(ref path) = history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+// Runs the commands read from stdin. Blank lines and lines starting with `#` are
+// skipped; a failing command is reported and the script goes on. Returns whether
+// every command succeeded.
+fn script(session: &mut Session) -> CliResult<bool> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut ok = true;
+    for (n, line) in stdin.lock().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let result =
+            split_line(&line).and_then(|words| session.execute(&words, &mut stdout.lock()));
+        match result {
+            Ok(Flow::Quit) => break,
+            Ok(Flow::Continue) => {}
+            Err(e) => {
+                eprintln!("line {}: {}", n + 1, e);
+                ok = false;
+            }
+        }
+    }
+    stdout.lock().flush()?;
+    Ok(ok)
+}
+
+fn run() -> CliResult<bool> {
+    let options = parse_args()?;
+    let kv = KVEngine::start_string(
+        &options.engine,
+        &options.config,
+        None::<fn(&str, &str, &str)>,
+    )
+    .map_err(|e| format!("cannot start engine {}: {}", options.engine, e))?;
+    let mut session = Session::new(kv, options.format);
+    if !options.command.is_empty() {
+        session.execute(&options.command, &mut io::stdout().lock())?;
+        Ok(true)
+    } else if io::stdin().is_terminal() {
+        repl(&mut session)?;
+        Ok(true)
+    } else {
+        script(&mut session)
+    }
+}
+
+fn main() {
+    match run() {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
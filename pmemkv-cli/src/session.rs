@@ -0,0 +1,384 @@
+use crate::format::{Format, FORMATS};
+use pmemkv::dump::{Dump, DumpFormat, DumpStats, Restore};
+use pmemkv::errors::{Error as KvError, ErrorKind};
+use pmemkv::kvengine::KVEngine;
+use pmemkv::migrate::Migration;
+use pmemkv::verify::Verifier;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::ops::ControlFlow;
+use std::os::raw::c_char;
+
+pub type CliResult<T> = Result<T, Box<dyn Error>>;
+
+pub const COMMANDS: &[&str] = &[
+    "count", "del", "dump", "exists", "format", "get", "help", "keys", "migrate", "put", "quit",
+    "restore", "scan", "verify",
+];
+
+const PROGRESS_EVERY: u64 = 10_000;
+
+const HELP: &str = "\
+put KEY VALUE      store VALUE under KEY
+get KEY            print the value of KEY
+del KEY            remove KEY
+exists KEY         print whether KEY is present
+count              print the number of keys
+scan [FROM] [TO]   print pairs with keys after FROM and before TO
+keys               print every key
+dump [--json] FILE [FROM [UNTIL]]
+                   write pairs with keys from FROM and before UNTIL to FILE
+restore FILE [FROM [UNTIL]]
+                   load the pairs of a dump, optionally only those in range
+migrate ENGINE CONFIG [BATCH]
+                   copy every pair into another engine and verify the copy
+verify [--json] [--unordered] [--checksums] [--ttl] [--samples N]
+                   check that counts, scans, key order and wrapper metadata agree
+format [MODE]      show or set the display mode: utf8, hex or base64
+help               show this help
+quit               leave the REPL";
+
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+/// An open engine and the display settings of one CLI run.
+pub struct Session {
+    kv: KVEngine,
+    format: Format,
+}
+
+/// Splits a command line into words. Words may be quoted with `"` or `'`, and `\`
+/// escapes the next character outside single quotes.
+pub fn split_line(line: &str) -> CliResult<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+                None => return Err("trailing backslash".into()),
+            },
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".into());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn to_bytes(chars: &[c_char]) -> Vec<u8> {
+    chars.iter().map(|&c| c as u8).collect()
+}
+
+// Shows a running count on stderr while a dump or restore is in progress.
+fn progress(stats: &DumpStats) {
+    if io::stderr().is_terminal() {
+        eprint!("\r{} pairs, {} bytes", stats.entries, stats.bytes);
+    }
+}
+
+fn end_progress() {
+    if io::stderr().is_terminal() {
+        eprintln!();
+    }
+}
+
+fn expect_args(args: &[String], min: usize, max: usize, usage: &str) -> CliResult<()> {
+    if args.len() < min || args.len() > max {
+        return Err(format!("usage: {}", usage).into());
+    }
+    Ok(())
+}
+
+impl Session {
+    pub fn new(kv: KVEngine, format: Format) -> Session {
+        Session { kv, format }
+    }
+
+    fn get(&self, key: &str) -> CliResult<Option<Vec<u8>>> {
+        let value = RefCell::new(None);
+        self.kv.get(
+            key,
+            Some(|v: &[c_char]| *value.borrow_mut() = Some(to_bytes(v))),
+        )?;
+        Ok(value.into_inner())
+    }
+
+    /// Runs one command, given as its name followed by its arguments, and writes its
+    /// output to `out`.
+    pub fn execute(&mut self, words: &[String], out: &mut dyn Write) -> CliResult<Flow> {
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (command.as_str(), args),
+            None => return Ok(Flow::Continue),
+        };
+        match command {
+            "put" => {
+                expect_args(args, 2, 2, "put KEY VALUE")?;
+                self.kv.put(&args[0], &args[1])?;
+            }
+            "get" => {
+                expect_args(args, 1, 1, "get KEY")?;
+                match self.get(&args[0])? {
+                    Some(value) => writeln!(out, "{}", self.format.encode(&value))?,
+                    None => return Err(format!("not found: {}", args[0]).into()),
+                }
+            }
+            "del" => {
+                expect_args(args, 1, 1, "del KEY")?;
+                self.kv.remove(&args[0])?;
+            }
+            "exists" => {
+                expect_args(args, 1, 1, "exists KEY")?;
+                let present = match self.kv.exists(&args[0]) {
+                    Ok(()) => true,
+                    Err(KvError(ErrorKind::NotFound(_), _)) => false,
+                    Err(e) => return Err(e.into()),
+                };
+                writeln!(out, "{}", present)?;
+            }
+            "count" => {
+                expect_args(args, 0, 0, "count")?;
+                writeln!(out, "{}", self.kv.count())?;
+            }
+            "scan" | "keys" => {
+                let values = command == "scan";
+                if values {
+                    expect_args(args, 0, 2, "scan [FROM] [TO]")?;
+                } else {
+                    expect_args(args, 0, 0, "keys")?;
+                }
+                let format = self.format;
+                let print = |k: &[u8], v: &[u8]| {
+                    let written = if values {
+                        writeln!(out, "{}\t{}", format.encode(k), format.encode(v))
+                    } else {
+                        writeln!(out, "{}", format.encode(k))
+                    };
+                    match written {
+                        Ok(()) => ControlFlow::Continue(()),
+                        Err(e) => ControlFlow::Break(e),
+                    }
+                };
+                let scanned = match args {
+                    [] => self.kv.each_until(print),
+                    [from] => self.kv.each_above_until(from, print),
+                    [from, to] => self.kv.each_between_until(from, to, print),
+                    _ => unreachable!(),
+                };
+                if let ControlFlow::Break(e) = scanned {
+                    return Err(e.into());
+                }
+            }
+            "dump" => {
+                let (format, args) = match args.split_first() {
+                    Some((flag, rest)) if flag == "--json" => (DumpFormat::JsonLines, rest),
+                    _ => (DumpFormat::Binary, args),
+                };
+                expect_args(args, 1, 3, "dump [--json] FILE [FROM [UNTIL]]")?;
+                let file = File::create(&args[0])?;
+                let mut dump = Dump::new(&self.kv)
+                    .format(format)
+                    .progress(PROGRESS_EVERY, progress);
+                if let Some(from) = args.get(1) {
+                    dump = dump.from(from);
+                }
+                if let Some(until) = args.get(2) {
+                    dump = dump.until(until);
+                }
+                let stats = dump.write_to(&file);
+                end_progress();
+                let stats = stats?;
+                file.sync_all()?;
+                writeln!(out, "dumped {} pairs, {} bytes", stats.entries, stats.bytes)?;
+            }
+            "restore" => {
+                expect_args(args, 1, 3, "restore FILE [FROM [UNTIL]]")?;
+                let file = File::open(&args[0])?;
+                let mut restore = Restore::new(&mut self.kv).progress(PROGRESS_EVERY, progress);
+                if let Some(from) = args.get(1) {
+                    restore = restore.from(from);
+                }
+                if let Some(until) = args.get(2) {
+                    restore = restore.until(until);
+                }
+                let stats = restore.read_from(file);
+                end_progress();
+                let stats = stats?;
+                writeln!(
+                    out,
+                    "restored {} pairs, {} bytes",
+                    stats.entries, stats.bytes
+                )?;
+            }
+            "migrate" => {
+                expect_args(args, 2, 3, "migrate ENGINE CONFIG [BATCH]")?;
+                let mut target =
+                    KVEngine::start_string(&args[0], &args[1], None::<fn(&str, &str, &str)>)
+                        .map_err(|e| format!("cannot start engine {}: {}", args[0], e))?;
+                let mut migration = Migration::new(&self.kv, &mut target);
+                if let Some(batch) = args.get(2) {
+                    migration = migration.batch_size(batch.parse()?);
+                }
+                if migration.is_interrupted() {
+                    writeln!(out, "resuming an interrupted migration")?;
+                }
+                let report = migration.run()?;
+                writeln!(
+                    out,
+                    "copied {} pairs in {} batches",
+                    report.copied, report.batches
+                )?;
+                let verification = migration.verify();
+                if !verification.is_match() {
+                    return Err(format!(
+                        "verification failed: expected {} pairs with digest {:016x}, \
+                         target has {} with digest {:016x}",
+                        verification.expected_count,
+                        verification.source_digest,
+                        verification.target_count,
+                        verification.target_digest
+                    )
+                    .into());
+                }
+                writeln!(out, "verified {} pairs", verification.target_count)?;
+            }
+            "verify" => {
+                let usage = "verify [--json] [--unordered] [--checksums] [--ttl] [--samples N]";
+                let mut verifier = Verifier::new(&self.kv);
+                let mut json = false;
+                let mut flags = args.iter();
+                while let Some(flag) = flags.next() {
+                    verifier = match flag.as_str() {
+                        "--json" => {
+                            json = true;
+                            verifier
+                        }
+                        "--unordered" => verifier.ordered(false),
+                        "--checksums" => verifier.checksums(true),
+                        "--ttl" => verifier.ttl(true),
+                        "--samples" => match flags.next() {
+                            Some(n) => verifier.samples(n.parse()?),
+                            None => return Err(format!("usage: {}", usage).into()),
+                        },
+                        _ => return Err(format!("usage: {}", usage).into()),
+                    };
+                }
+                let report = verifier.run();
+                if json {
+                    writeln!(out, "{}", report.to_json())?;
+                } else {
+                    writeln!(
+                        out,
+                        "count {}, visited {} pairs, checked {} range counts and {} checksums",
+                        report.count, report.visited, report.ranges_checked, report.values_checked
+                    )?;
+                    let ns = &report.namespaces;
+                    writeln!(
+                        out,
+                        "reserved keys: {} batch journal, {} merge operands, {} expiry index, \
+                         {} migration checkpoint, {} unknown",
+                        ns.batch_journal,
+                        ns.merge_operands,
+                        ns.expiry_index,
+                        ns.migrate_checkpoint,
+                        ns.unknown
+                    )?;
+                    for issue in &report.issues {
+                        let key = match issue.key {
+                            Some(ref key) => self.format.encode(key),
+                            None => "-".to_string(),
+                        };
+                        writeln!(out, "{}\t{}\t{}", issue.check.name(), key, issue.detail)?;
+                    }
+                }
+                if !report.is_consistent() {
+                    return Err(format!("{} issues found", report.issues.len()).into());
+                }
+                if !json {
+                    writeln!(out, "consistent")?;
+                }
+            }
+            "format" => {
+                expect_args(args, 0, 1, "format [utf8|hex|base64]")?;
+                match args.first() {
+                    Some(name) => {
+                        self.format = Format::parse(name).ok_or_else(|| {
+                            format!("unknown format {}, expected one of {:?}", name, FORMATS)
+                        })?
+                    }
+                    None => writeln!(out, "{}", self.format.name())?,
+                }
+            }
+            "help" => writeln!(out, "{}", HELP)?,
+            "quit" | "exit" => return Ok(Flow::Quit),
+            _ => return Err(format!("unknown command: {} (try help)", command).into()),
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Vec<String> {
+        split_line(line).unwrap()
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(split("  put  key value "), vec!["put", "key", "value"]);
+        assert!(split("").is_empty());
+        assert!(split(" \t ").is_empty());
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            split(r#"put "a key" 'a value'"#),
+            vec!["put", "a key", "a value"]
+        );
+        assert_eq!(split(r#"put "" ''"#), vec!["put", "", ""]);
+        assert_eq!(split(r#"a"b c"d"#), vec!["ab cd"]);
+        assert_eq!(split(r#""it's" 'say "hi"'"#), vec!["it's", r#"say "hi""#]);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(split(r"a\ b c\\d"), vec!["a b", r"c\d"]);
+        assert_eq!(split(r#""a \"b\"""#), vec![r#"a "b""#]);
+        // Backslashes are kept as they are inside single quotes.
+        assert_eq!(split(r"'a\b'"), vec![r"a\b"]);
+        assert_eq!(split(r"\'"), vec!["'"]);
+    }
+
+    #[test]
+    fn errors() {
+        for line in &[r#"put "key"#, "put 'key", r"put key\", r#""a\""#] {
+            assert!(split_line(line).is_err(), "{}", line);
+        }
+        assert_eq!(
+            split_line("get 'key").unwrap_err().to_string(),
+            "unterminated quote"
+        );
+        assert_eq!(
+            split_line(r"get key\").unwrap_err().to_string(),
+            "trailing backslash"
+        );
+    }
+}
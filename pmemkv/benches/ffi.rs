@@ -0,0 +1,215 @@
+//! Per-call cost of the binding layer on `vsmap`: the `&str` entry points go through
+//! closure trampolines and, for `get_copy`, a NUL-terminated scratch buffer; the
+//! `_bytes` ones hand slices straight to the raw callbacks. Every variant of an
+//! operation runs on the same engine, with nothing in between.
+//!
+//! Set `PMEMKV_BENCH_CONFIG` to point `vsmap` somewhere other than `/dev/shm`.
+
+#[macro_use]
+extern crate criterion;
+extern crate pmemkv;
+
+use criterion::{black_box, BenchmarkId, Criterion, Throughput};
+use pmemkv::kvengine::KVEngine;
+use std::env;
+use std::ops::ControlFlow;
+use std::os::raw::c_char;
+
+const VALUE_SIZES: &[usize] = &[8, 64, 512, 4096];
+// Keys point operations cycle through, and pairs visited by every `each` call.
+const KEYS: usize = 1000;
+
+fn start() -> KVEngine {
+    let config = env::var("PMEMKV_BENCH_CONFIG")
+        .unwrap_or_else(|_| r#"{"path":"/dev/shm","size":1073741824}"#.to_string());
+    KVEngine::start_string("vsmap", &config, None::<fn(&str, &str, &str)>)
+        .expect("cannot start vsmap")
+}
+
+fn keys() -> Vec<String> {
+    (0..KEYS).map(|i| format!("key{:08}", i)).collect()
+}
+
+// Values are ASCII without NUL bytes so that the `&str` variants accept them.
+fn value(size: usize) -> String {
+    (0..size)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect()
+}
+
+fn filled(keys: &[String], value: &str) -> KVEngine {
+    let mut kv = start();
+    for key in keys {
+        kv.put(key, value).unwrap();
+    }
+    kv
+}
+
+// Hands out the keys one after the other, starting over at the end.
+fn cycle<'a>(keys: &'a [String]) -> impl FnMut() -> &'a str {
+    let mut i = 0;
+    move || {
+        i = (i + 1) % keys.len();
+        &keys[i]
+    }
+}
+
+fn bench_put(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("put");
+    for &size in VALUE_SIZES {
+        let value = value(size);
+        let mut kv = filled(&keys, &value);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::new("str", size), |b| {
+            let mut next = cycle(&keys);
+            b.iter(|| kv.put(next(), &value).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("bytes", size), |b| {
+            let mut next = cycle(&keys);
+            b.iter(|| kv.put_bytes(next().as_bytes(), value.as_bytes()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_get(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("get");
+    for &size in VALUE_SIZES {
+        let kv = filled(&keys, &value(size));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::new("str", size), |b| {
+            let mut next = cycle(&keys);
+            b.iter(|| {
+                kv.get_string(
+                    next(),
+                    Some(|v: &str| {
+                        black_box(v);
+                    }),
+                )
+                .unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("c_char", size), |b| {
+            let mut next = cycle(&keys);
+            b.iter(|| {
+                kv.get(
+                    next(),
+                    Some(|v: &[c_char]| {
+                        black_box(v);
+                    }),
+                )
+                .unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("bytes", size), |b| {
+            let mut next = cycle(&keys);
+            b.iter(|| {
+                kv.get_bytes(next().as_bytes(), |v| {
+                    black_box(v);
+                })
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_get_copy(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("get_copy");
+    for &size in VALUE_SIZES {
+        let kv = filled(&keys, &value(size));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::new("str", size), |b| {
+            let mut next = cycle(&keys);
+            b.iter(|| kv.get_copy(next(), size as i32 + 1).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("into", size), |b| {
+            let mut next = cycle(&keys);
+            let mut buf = Vec::new();
+            b.iter(|| kv.get_into(next(), &mut buf).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("bytes", size), |b| {
+            let mut next = cycle(&keys);
+            b.iter(|| {
+                let mut copy = Vec::new();
+                kv.get_bytes(next().as_bytes(), |v| copy.extend_from_slice(v))
+                    .unwrap();
+                copy
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_exists(c: &mut Criterion) {
+    let keys = keys();
+    let kv = filled(&keys, &value(8));
+    let missing: Vec<_> = keys.iter().map(|k| format!("{}-missing", k)).collect();
+    let mut group = c.benchmark_group("exists");
+    group.bench_function("str/found", |b| {
+        let mut next = cycle(&keys);
+        b.iter(|| kv.exists(next()).is_ok())
+    });
+    group.bench_function("str/missing", |b| {
+        let mut next = cycle(&missing);
+        b.iter(|| kv.exists(next()).is_ok())
+    });
+    group.bench_function("bytes/found", |b| {
+        let mut next = cycle(&keys);
+        b.iter(|| kv.exists_bytes(next().as_bytes()).is_ok())
+    });
+    group.bench_function("bytes/missing", |b| {
+        let mut next = cycle(&missing);
+        b.iter(|| kv.exists_bytes(next().as_bytes()).is_ok())
+    });
+    group.finish();
+}
+
+fn bench_each(c: &mut Criterion) {
+    let keys = keys();
+    let mut group = c.benchmark_group("each");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    for &size in VALUE_SIZES {
+        let kv = filled(&keys, &value(size));
+        group.bench_function(BenchmarkId::new("str", size), |b| {
+            b.iter(|| {
+                kv.each_string(Some(|k: &str, v: &str| {
+                    black_box((k, v));
+                }))
+            })
+        });
+        group.bench_function(BenchmarkId::new("c_char", size), |b| {
+            b.iter(|| {
+                kv.each(Some(|k: &[c_char], v: &[c_char]| {
+                    black_box((k, v));
+                }))
+            })
+        });
+        // The `ControlFlow` scans use a single trampoline over the byte layer.
+        group.bench_function(BenchmarkId::new("until", size), |b| {
+            b.iter(|| {
+                let mut visited = 0;
+                let _ = kv.each_until(|k, v| {
+                    black_box((k, v));
+                    visited += 1;
+                    ControlFlow::<()>::Continue(())
+                });
+                visited
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_put,
+    bench_get,
+    bench_get_copy,
+    bench_exists,
+    bench_each
+);
+criterion_main!(benches);
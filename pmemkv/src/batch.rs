@@ -0,0 +1,274 @@
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use crate::sequence;
+use std::collections::BTreeMap;
+use std::ops::ControlFlow;
+
+/// Keys reserved for the journal of crash-atomic batches. They start with `0xFF`, so
+/// no key passed as `&str` can collide with them.
+pub const JOURNAL_PREFIX: &[u8] = b"\xff\xfepmemkv-batch:";
+
+const TAG_PUT: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// Puts and removes collected to be applied to an engine in one call.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// Outcome of `WriteBatch::recover`, counted in batches.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Recovery {
+    pub replayed: usize,
+    pub rolled_back: usize,
+}
+
+// Journal layout: the commit marker of batch `seq` is `JOURNAL_PREFIX + seq`, and its
+// operations are stored under `JOURNAL_PREFIX + seq + index`, all big-endian.
+fn marker_key(seq: u64) -> Vec<u8> {
+    let mut key = JOURNAL_PREFIX.to_vec();
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+fn entry_key(seq: u64, index: u32) -> Vec<u8> {
+    let mut key = marker_key(seq);
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+fn encode_op(op: &BatchOp) -> Vec<u8> {
+    let (tag, key, value): (u8, &[u8], &[u8]) = match op {
+        BatchOp::Put(k, v) => (TAG_PUT, k, v),
+        BatchOp::Remove(k) => (TAG_REMOVE, k, &[]),
+    };
+    let mut buf = Vec::with_capacity(5 + key.len() + value.len());
+    buf.push(tag);
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    buf
+}
+
+fn decode_op(buf: &[u8]) -> Option<BatchOp> {
+    if buf.len() < 5 {
+        return None;
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    let rest = &buf[5..];
+    if rest.len() < len {
+        return None;
+    }
+    let (key, value) = rest.split_at(len);
+    match buf[0] {
+        TAG_PUT => Some(BatchOp::Put(key.to_vec(), value.to_vec())),
+        TAG_REMOVE if value.is_empty() => Some(BatchOp::Remove(key.to_vec())),
+        _ => None,
+    }
+}
+
+fn apply_op(engine: &mut KVEngine, op: &BatchOp) -> Result<()> {
+    match op {
+        BatchOp::Put(k, v) => engine.put_raw(k, v),
+        BatchOp::Remove(k) => engine.remove_raw(k),
+    }
+}
+
+// Splits a journal key into its batch sequence number and, for operation entries, the
+// operation index.
+pub(crate) fn parse_journal_key(key: &[u8]) -> Option<(u64, Option<u32>)> {
+    let rest = key.get(JOURNAL_PREFIX.len()..)?;
+    if !key.starts_with(JOURNAL_PREFIX) || rest.len() < 8 {
+        return None;
+    }
+    let (seq, index) = rest.split_at(8);
+    let seq = u64::from_be_bytes([
+        seq[0], seq[1], seq[2], seq[3], seq[4], seq[5], seq[6], seq[7],
+    ]);
+    match index.len() {
+        0 => Some((seq, None)),
+        4 => Some((
+            seq,
+            Some(u32::from_be_bytes([index[0], index[1], index[2], index[3]])),
+        )),
+        _ => None,
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn put(&mut self, key: &str, value: &str) -> &mut WriteBatch {
+        self.put_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    pub fn put_bytes(&mut self, key: &[u8], value: &[u8]) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Put(key.to_vec(), value.to_vec()));
+        self
+    }
+
+    pub fn remove(&mut self, key: &str) -> &mut WriteBatch {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove(key.to_vec()));
+        self
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    /// Applies the operations in order and returns the status of each one. A failed
+    /// operation does not stop the ones after it.
+    pub fn apply(&self, engine: &mut KVEngine) -> Vec<Result<()>> {
+        self.ops.iter().map(|op| apply_op(engine, op)).collect()
+    }
+
+    /// Like `apply`, but the batch is first journaled under `JOURNAL_PREFIX` and
+    /// committed, so a crash part-way leaves either all or none of it once
+    /// `recover` runs on the reopened engine. An error means the batch was not
+    /// committed and nothing was applied.
+    pub fn apply_atomic(&self, engine: &mut KVEngine) -> Result<Vec<Result<()>>> {
+        let seq = sequence::next();
+        for (i, op) in self.ops.iter().enumerate() {
+            if let Err(e) = engine.put_raw(&entry_key(seq, i as u32), &encode_op(op)) {
+                WriteBatch::discard(engine, seq, i);
+                return Err(e);
+            }
+        }
+        if let Err(e) = engine.put_raw(&marker_key(seq), &[]) {
+            WriteBatch::discard(engine, seq, self.ops.len());
+            return Err(e);
+        }
+        let statuses = self.apply(engine);
+        WriteBatch::discard(engine, seq, self.ops.len());
+        Ok(statuses)
+    }
+
+    // Removes the commit marker first, then the journaled operations.
+    fn discard(engine: &mut KVEngine, seq: u64, len: usize) {
+        let _ = engine.remove_raw(&marker_key(seq));
+        for i in 0..len {
+            let _ = engine.remove_raw(&entry_key(seq, i as u32));
+        }
+    }
+
+    /// Finishes the crash-atomic batches left in the journal by a previous run:
+    /// committed batches are applied again, uncommitted ones are dropped. Call it
+    /// after reopening the engine and before serving requests.
+    pub fn recover(engine: &mut KVEngine) -> Result<Recovery> {
+        let mut batches: BTreeMap<u64, (bool, Vec<Option<BatchOp>>)> = BTreeMap::new();
+        engine.each_raw(&mut |k, v| {
+            if let Some((seq, index)) = parse_journal_key(k) {
+                let batch = batches.entry(seq).or_default();
+                match index {
+                    None => batch.0 = true,
+                    Some(i) => {
+                        let i = i as usize;
+                        if batch.1.len() <= i {
+                            batch.1.resize(i + 1, None);
+                        }
+                        batch.1[i] = decode_op(v);
+                    }
+                }
+            }
+            ControlFlow::Continue(())
+        });
+        let mut recovery = Recovery::default();
+        for (seq, (committed, ops)) in batches {
+            if committed {
+                if ops.iter().any(|op| op.is_none()) {
+                    bail!(ErrorKind::Fail);
+                }
+                for op in ops.iter().flatten() {
+                    match apply_op(engine, op) {
+                        Err(Error(ErrorKind::NotFound(_), _)) | Ok(()) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                recovery.replayed += 1;
+            } else {
+                recovery.rolled_back += 1;
+            }
+            WriteBatch::discard(engine, seq, ops.len());
+        }
+        Ok(recovery)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn op_round_trip() {
+        let ops = vec![
+            BatchOp::Put(b"key".to_vec(), b"value".to_vec()),
+            BatchOp::Put(Vec::new(), Vec::new()),
+            BatchOp::Put(vec![0, 0xFF], vec![0; 300]),
+            BatchOp::Remove(b"key".to_vec()),
+            BatchOp::Remove(Vec::new()),
+        ];
+        for op in ops {
+            assert_eq!(decode_op(&encode_op(&op)), Some(op));
+        }
+    }
+
+    #[test]
+    fn bad_ops() {
+        let put = encode_op(&BatchOp::Put(b"key".to_vec(), b"v".to_vec()));
+        assert_eq!(decode_op(&put[..4]), None);
+        assert_eq!(decode_op(&put[..7]), None);
+        assert_eq!(decode_op(&[]), None);
+        let mut remove = encode_op(&BatchOp::Remove(b"key".to_vec()));
+        remove.push(b'x');
+        assert_eq!(decode_op(&remove), None);
+        let mut unknown = put.clone();
+        unknown[0] = 3;
+        assert_eq!(decode_op(&unknown), None);
+    }
+
+    #[test]
+    fn journal_keys() {
+        assert_eq!(parse_journal_key(&marker_key(42)), Some((42, None)));
+        assert_eq!(
+            parse_journal_key(&entry_key(u64::MAX, 7)),
+            Some((u64::MAX, Some(7)))
+        );
+        let entry = entry_key(42, 7);
+        // Truncated in the sequence number or the index.
+        assert_eq!(parse_journal_key(&entry[..JOURNAL_PREFIX.len() + 5]), None);
+        assert_eq!(parse_journal_key(&entry[..entry.len() - 1]), None);
+        assert_eq!(parse_journal_key(&entry[..JOURNAL_PREFIX.len() - 1]), None);
+        let mut long = entry.clone();
+        long.push(0);
+        assert_eq!(parse_journal_key(&long), None);
+        let mut foreign = entry;
+        foreign[2] = b'q';
+        assert_eq!(parse_journal_key(&foreign), None);
+        assert_eq!(parse_journal_key(b"user-key"), None);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::errors::*;
+use crate::kvengine::{EngineRef, KVEngine, SharedEngine};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+const DEFAULT_CAPACITY: usize = 1024;
+const DEFAULT_MAX_BYTES: usize = 64 << 20;
+
+/// Counters kept by `CachedEngine`. `entries` and `bytes` describe the cache at the
+/// time `stats` was called.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub negative_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl CacheStats {
+    /// Share of lookups answered from the cache, negative hits included.
+    pub fn hit_ratio(&self) -> f64 {
+        let hits = self.hits + self.negative_hits;
+        match hits + self.misses {
+            0 => 0.0,
+            total => hits as f64 / total as f64,
+        }
+    }
+}
+
+struct Slot {
+    // `None` records a key known to be absent.
+    value: Option<Vec<u8>>,
+    tick: u64,
+}
+
+// Least recently used order is kept by a monotonic tick per access: `order` maps the
+// tick of each slot back to its key, so its first entry is the next one to evict.
+#[derive(Default)]
+struct Lru {
+    slots: HashMap<Vec<u8>, Slot>,
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    bytes: usize,
+    stats: CacheStats,
+}
+
+fn footprint(key: &[u8], value: &Option<Vec<u8>>) -> usize {
+    key.len() + value.as_ref().map_or(0, Vec::len)
+}
+
+impl Lru {
+    fn get(&mut self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let slot = self.slots.get_mut(key)?;
+        self.order.remove(&slot.tick);
+        self.order.insert(tick, key.to_vec());
+        slot.tick = tick;
+        Some(slot.value.clone())
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(slot) = self.slots.remove(key) {
+            self.order.remove(&slot.tick);
+            self.bytes -= footprint(key, &slot.value);
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: Option<Vec<u8>>, capacity: usize, max_bytes: usize) {
+        self.remove(key);
+        let size = footprint(key, &value);
+        if capacity == 0 || size > max_bytes {
+            return;
+        }
+        while self.slots.len() >= capacity || self.bytes + size > max_bytes {
+            let oldest = match self.order.keys().next() {
+                Some(&tick) => self.order.remove(&tick),
+                None => break,
+            };
+            if let Some(k) = oldest {
+                if let Some(slot) = self.slots.remove(&k) {
+                    self.bytes -= footprint(&k, &slot.value);
+                    self.stats.evictions += 1;
+                }
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_vec());
+        self.slots.insert(
+            key.to_vec(),
+            Slot {
+                value,
+                tick: self.tick,
+            },
+        );
+        self.bytes += size;
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Read-through cache in front of a `KVEngine`, bounded by entry count and by the
+/// bytes of the keys and values it holds. Writes go to the engine and invalidate the
+/// cached entry, so the engine must not be written behind the wrapper's back. Engine
+/// accesses are serialized by a lock; cache hits do not take it.
+pub struct CachedEngine {
+    engine: SharedEngine,
+    capacity: usize,
+    max_bytes: usize,
+    negative: bool,
+    lru: Mutex<Lru>,
+}
+
+impl CachedEngine {
+    pub fn new(engine: KVEngine) -> CachedEngine {
+        CachedEngine {
+            engine: SharedEngine::new(engine),
+            capacity: DEFAULT_CAPACITY,
+            max_bytes: DEFAULT_MAX_BYTES,
+            negative: false,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Maximum number of cached entries.
+    pub fn capacity(mut self, capacity: usize) -> CachedEngine {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Maximum total size of cached keys and values.
+    pub fn max_bytes(mut self, max_bytes: usize) -> CachedEngine {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Also caches lookups of absent keys, so repeated misses skip the engine.
+    pub fn negative_caching(mut self, enabled: bool) -> CachedEngine {
+        self.negative = enabled;
+        self
+    }
+
+    /// Locks the engine for reads until the returned guard is dropped.
+    pub fn engine(&self) -> EngineRef<'_> {
+        self.engine.read()
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine.into_inner()
+    }
+
+    fn lru(&self) -> MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Cache fills and invalidations both happen under the engine lock, so a fill racing
+    // with a write can never leave the old value behind in the cache.
+    fn read(&self, key: &[u8]) -> Option<Vec<u8>> {
+        {
+            let mut lru = self.lru();
+            if let Some(cached) = lru.get(key) {
+                match cached {
+                    Some(_) => lru.stats.hits += 1,
+                    None => lru.stats.negative_hits += 1,
+                }
+                return cached;
+            }
+            lru.stats.misses += 1;
+        }
+        let engine = self.engine.lock();
+        let value = engine.get_copy_raw(key);
+        if value.is_some() || self.negative {
+            self.lru()
+                .insert(key, value.clone(), self.capacity, self.max_bytes);
+        }
+        value
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.read(key.as_bytes()))
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.read(key.as_bytes()).is_some()
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        let key = key.as_bytes();
+        let mut engine = self.engine.lock();
+        let result = engine.put_raw(key, value);
+        self.lru().remove(key);
+        result
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let key = key.as_bytes();
+        let mut engine = self.engine.lock();
+        let result = engine.remove_raw(key);
+        self.lru().remove(key);
+        result
+    }
+
+    /// Drops the cached entry of `key`, if any.
+    pub fn invalidate(&self, key: &str) {
+        self.lru().remove(key.as_bytes());
+    }
+
+    /// Drops every cached entry. Statistics are kept.
+    pub fn clear(&self) {
+        self.lru().clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru();
+        CacheStats {
+            entries: lru.slots.len(),
+            bytes: lru.bytes,
+            ..lru.stats
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.lru().stats = CacheStats::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(v: &str) -> Option<Vec<u8>> {
+        Some(v.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::default();
+        lru.insert(b"a", value("1"), 2, usize::MAX);
+        lru.insert(b"b", value("2"), 2, usize::MAX);
+        assert_eq!(lru.get(b"a"), Some(value("1")));
+        lru.insert(b"c", value("3"), 2, usize::MAX);
+        assert_eq!(lru.get(b"b"), None);
+        assert_eq!(lru.get(b"a"), Some(value("1")));
+        assert_eq!(lru.get(b"c"), Some(value("3")));
+        assert_eq!(lru.stats.evictions, 1);
+        assert_eq!(lru.bytes, 4);
+    }
+
+    #[test]
+    fn byte_bound() {
+        let mut lru = Lru::default();
+        lru.insert(b"a", value("12345"), 10, 8);
+        lru.insert(b"b", value("12"), 10, 8);
+        assert_eq!(lru.get(b"a"), None);
+        assert_eq!(lru.bytes, 3);
+        // Entries larger than the bound are not cached, and replace no other entry.
+        lru.insert(b"c", value("123456789"), 10, 8);
+        assert_eq!(lru.get(b"c"), None);
+        assert_eq!(lru.get(b"b"), Some(value("12")));
+        lru.insert(b"b", value("1"), 10, 8);
+        assert_eq!(lru.bytes, 2);
+    }
+
+    #[test]
+    fn absent_keys_and_clear() {
+        let mut lru = Lru::default();
+        lru.insert(b"a", None, 10, 100);
+        assert_eq!(lru.get(b"a"), Some(None));
+        lru.insert(b"b", value("2"), 0, 100);
+        assert_eq!(lru.get(b"b"), None);
+        lru.remove(b"a");
+        assert_eq!(lru.get(b"a"), None);
+        lru.insert(b"a", value("1"), 10, 100);
+        lru.clear();
+        assert_eq!(lru.get(b"a"), None);
+        assert_eq!((lru.slots.len(), lru.order.len(), lru.bytes), (0, 0, 0));
+    }
+}
@@ -0,0 +1,288 @@
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Every stored value starts with the tag of the codec that produced it, so values
+// written with different settings, or before a codec was enabled, stay readable.
+const TAG_RAW: u8 = 0;
+#[cfg(feature = "lz4")]
+const TAG_LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const TAG_ZSTD: u8 = 2;
+
+const DEFAULT_THRESHOLD: usize = 64;
+const DEFAULT_MAX_VALUE_BYTES: usize = 64 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    /// Values are stored as they are, behind the header byte.
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard at the given compression level.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+/// Counters kept by `CompressedEngine` for the values it has written.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompressionStats {
+    pub values: u64,
+    pub compressed: u64,
+    /// Size of the values as passed to `put`.
+    pub bytes_in: u64,
+    /// Size of the values as stored, header bytes included.
+    pub bytes_out: u64,
+}
+
+impl CompressionStats {
+    /// Uncompressed over stored size; above 1.0 when compression saves space.
+    pub fn ratio(&self) -> f64 {
+        match self.bytes_out {
+            0 => 1.0,
+            out => self.bytes_in as f64 / out as f64,
+        }
+    }
+}
+
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn compress(codec: Codec, value: &[u8]) -> Option<(u8, Vec<u8>)> {
+    match codec {
+        Codec::None => None,
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 => Some((TAG_LZ4, lz4_flex::compress_prepend_size(value))),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd(level) => zstd::bulk::compress(value, level)
+            .ok()
+            .map(|c| (TAG_ZSTD, c)),
+    }
+}
+
+// Fails rather than decode more than `max` bytes, so a damaged size header cannot
+// make a read allocate without bound.
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn decompress(key: &[u8], stored: &[u8], max: usize) -> Result<Vec<u8>> {
+    let invalid = || ErrorKind::InvalidFormat(String::from_utf8_lossy(key).into_owned());
+    let (&tag, body) = match stored.split_first() {
+        Some(split) => split,
+        None => bail!(invalid()),
+    };
+    match tag {
+        TAG_RAW => Ok(body.to_vec()),
+        #[cfg(feature = "lz4")]
+        TAG_LZ4 => {
+            let size = match body.get(..4) {
+                Some(size) => u32::from_le_bytes([size[0], size[1], size[2], size[3]]),
+                None => bail!(invalid()),
+            };
+            if size as usize > max {
+                bail!(invalid());
+            }
+            lz4_flex::decompress_size_prepended(body).map_err(|_| invalid().into())
+        }
+        #[cfg(feature = "zstd")]
+        TAG_ZSTD => {
+            use std::io::Read;
+            let mut value = Vec::new();
+            zstd::stream::read::Decoder::new(body)
+                .and_then(|d| d.take(max as u64 + 1).read_to_end(&mut value))
+                .map_err(|_| invalid())?;
+            if value.len() > max {
+                bail!(invalid());
+            }
+            Ok(value)
+        }
+        // Written by a build with a codec that is not enabled here.
+        _ => bail!(invalid()),
+    }
+}
+
+/// Engine wrapper compressing values on `put` and decompressing them on reads. Values
+/// shorter than the threshold, or that do not shrink, are stored uncompressed.
+pub struct CompressedEngine {
+    engine: KVEngine,
+    codec: Codec,
+    threshold: usize,
+    max_value_bytes: usize,
+    values: AtomicU64,
+    compressed: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl CompressedEngine {
+    pub fn new(engine: KVEngine) -> CompressedEngine {
+        CompressedEngine {
+            engine,
+            codec: Codec::None,
+            threshold: DEFAULT_THRESHOLD,
+            max_value_bytes: DEFAULT_MAX_VALUE_BYTES,
+            values: AtomicU64::new(0),
+            compressed: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        }
+    }
+
+    /// Codec used for new writes. Reads handle every enabled codec.
+    pub fn codec(mut self, codec: Codec) -> CompressedEngine {
+        self.codec = codec;
+        self
+    }
+
+    /// Values shorter than `threshold` bytes are never compressed.
+    pub fn threshold(mut self, threshold: usize) -> CompressedEngine {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Largest value reads decompress, 64 MiB by default. Longer ones fail with
+    /// `ErrorKind::InvalidFormat`.
+    pub fn max_value_bytes(mut self, bytes: usize) -> CompressedEngine {
+        self.max_value_bytes = bytes;
+        self
+    }
+
+    pub fn engine(&self) -> &KVEngine {
+        &self.engine
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine
+    }
+
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        let compressed = if value.len() >= self.threshold {
+            compress(self.codec, value).filter(|(_, c)| c.len() < value.len())
+        } else {
+            None
+        };
+        let (tag, body) = match compressed {
+            Some((tag, ref c)) => (tag, c.as_slice()),
+            None => (TAG_RAW, value),
+        };
+        let mut buf = Vec::with_capacity(1 + body.len());
+        buf.push(tag);
+        buf.extend_from_slice(body);
+        self.values.fetch_add(1, Ordering::Relaxed);
+        if tag != TAG_RAW {
+            self.compressed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes_in
+            .fetch_add(value.len() as u64, Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        buf
+    }
+
+    pub fn put(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.engine.put_raw(key.as_bytes(), &self.encode(value))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = key.as_bytes();
+        match self.engine.get_copy_raw(key) {
+            Some(stored) => decompress(key, &stored, self.max_value_bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.engine.exists_raw(key.as_bytes())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.engine.remove_raw(key.as_bytes())
+    }
+
+    pub fn count(&self) -> i64 {
+        self.engine.count()
+    }
+
+    /// Visits every pair with its value decompressed. Stops at the first value that
+    /// cannot be decoded and returns its error.
+    pub fn each<F>(&self, mut callback: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let mut result = Ok(());
+        let max = self.max_value_bytes;
+        self.engine
+            .each_raw(&mut |k, v| match decompress(k, v, max) {
+                Ok(value) => {
+                    callback(k, &value);
+                    ControlFlow::Continue(())
+                }
+                Err(e) => {
+                    result = Err(e);
+                    ControlFlow::Break(())
+                }
+            });
+        result
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            values: self.values.load(Ordering::Relaxed),
+            compressed: self.compressed.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codecs() -> Vec<Codec> {
+        vec![
+            #[cfg(feature = "lz4")]
+            Codec::Lz4,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(3),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = b"abcdefgh".repeat(100);
+        for codec in codecs() {
+            let (tag, body) = compress(codec, &value).unwrap();
+            assert!(body.len() < value.len());
+            let mut stored = vec![tag];
+            stored.extend_from_slice(&body);
+            assert_eq!(decompress(b"key", &stored, value.len()).unwrap(), value);
+        }
+        assert_eq!(compress(Codec::None, &value), None);
+        assert_eq!(decompress(b"key", b"\x00raw", 0).unwrap(), b"raw".to_vec());
+    }
+
+    #[test]
+    fn max_value_bytes() {
+        let value = b"abcdefgh".repeat(100);
+        for codec in codecs() {
+            let (tag, body) = compress(codec, &value).unwrap();
+            let mut stored = vec![tag];
+            stored.extend_from_slice(&body);
+            match decompress(b"key", &stored, value.len() - 1)
+                .unwrap_err()
+                .kind()
+            {
+                ErrorKind::InvalidFormat(k) => assert_eq!(k, "key"),
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_values() {
+        assert!(decompress(b"key", b"", 64).is_err());
+        assert!(decompress(b"key", b"\x7fbody", 64).is_err());
+        for codec in codecs() {
+            let (tag, _) = compress(codec, b"").unwrap();
+            assert!(decompress(b"key", &[tag, 0], 1 << 20).is_err());
+        }
+    }
+}
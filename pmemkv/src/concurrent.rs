@@ -0,0 +1,96 @@
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use crate::update::lock_key;
+
+// Engines whose writes may run concurrently with other reads and writes.
+const CONCURRENT_ENGINES: &[&str] = &["cmap", "vcmap"];
+
+/// Handle to one of the engines that support concurrent access, such as `cmap`. Unlike
+/// `KVEngine` it can be shared between threads, and it writes through `&self`. Writes
+/// take a per-key lock, so `put`, `remove` and the read-modify-write operations are
+/// linearizable with respect to each other.
+pub struct ConcurrentEngine {
+    engine: KVEngine,
+}
+
+// Only constructed for the engines in CONCURRENT_ENGINES.
+unsafe impl Sync for ConcurrentEngine {}
+
+impl ConcurrentEngine {
+    /// Whether `engine` supports concurrent access.
+    pub fn supports(engine: &str) -> bool {
+        CONCURRENT_ENGINES.contains(&engine)
+    }
+
+    /// Starts `engine` like `KVEngine::start_string`. Fails with
+    /// `ErrorKind::NotConcurrent` for engines that do not support concurrent access.
+    pub fn start_string<F>(
+        engine: &str,
+        config: &str,
+        callback: Option<F>,
+    ) -> Result<ConcurrentEngine>
+    where
+        F: Fn(&str, &str, &str),
+        F: 'static,
+    {
+        if !ConcurrentEngine::supports(engine) {
+            bail!(ErrorKind::NotConcurrent(engine.to_string()));
+        }
+        let engine = KVEngine::start_string(engine, config, callback)?;
+        Ok(ConcurrentEngine { engine })
+    }
+
+    /// The engine, for reads.
+    pub fn engine(&self) -> &KVEngine {
+        &self.engine
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        let key = key.as_bytes();
+        let _guard = lock_key(&self.engine, key);
+        unsafe { self.engine.put_shared(key, value) }
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let key = key.as_bytes();
+        let _guard = lock_key(&self.engine, key);
+        unsafe { self.engine.remove_shared(key) }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.engine.get_copy_raw(key.as_bytes()))
+    }
+
+    /// See `KVEngine::update`.
+    pub fn update<F>(&self, key: &str, f: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let key = key.as_bytes();
+        let _guard = lock_key(&self.engine, key);
+        unsafe { self.engine.update_shared(key, f) }
+    }
+
+    /// See `KVEngine::compare_and_swap`.
+    pub fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let key = key.as_bytes();
+        let _guard = lock_key(&self.engine, key);
+        unsafe { self.engine.compare_and_swap_shared(key, expected, new) }
+    }
+
+    /// See `KVEngine::get_and_put`.
+    pub fn get_and_put(&self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.as_bytes();
+        let _guard = lock_key(&self.engine, key);
+        unsafe { self.engine.get_and_put_shared(key, value) }
+    }
+}
@@ -0,0 +1,254 @@
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use crate::range::Entry;
+use std::ops::ControlFlow;
+
+const TOKEN_VERSION: u8 = 1;
+const HAS_AFTER: u8 = 1;
+const HAS_END: u8 = 1 << 1;
+const DONE: u8 = 1 << 2;
+
+/// Resumable position in an ordered scan. Each call to `next_page` returns at most
+/// `limit` pairs following the last key already returned, and the position can be
+/// saved with `token` and restored with `from_token` to continue later.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    limit: usize,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    after: Option<Vec<u8>>,
+    done: bool,
+}
+
+fn push_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    buf.extend_from_slice(field);
+}
+
+fn take_field<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let (len, rest) = buf.split_at(4);
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (field, rest) = rest.split_at(len);
+    *buf = rest;
+    Some(field)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+impl Cursor {
+    /// A cursor over every key, returning pages of up to `limit` pairs.
+    pub fn new(limit: usize) -> Cursor {
+        Cursor {
+            limit: limit.max(1),
+            start: Vec::new(),
+            end: None,
+            after: None,
+            done: false,
+        }
+    }
+
+    /// Starts the scan at `key`, inclusive.
+    pub fn from(mut self, key: &str) -> Cursor {
+        self.start = key.as_bytes().to_vec();
+        self
+    }
+
+    /// Stops the scan before `key`.
+    pub fn until(mut self, key: &str) -> Cursor {
+        self.end = Some(key.as_bytes().to_vec());
+        self
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The last key returned so far.
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.after.as_deref()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Returns the next page of pairs in key order. An empty page means the scan has
+    /// finished.
+    pub fn next_page(&mut self, engine: &KVEngine) -> Vec<Entry> {
+        let mut page = Vec::new();
+        if self.done {
+            return page;
+        }
+        let limit = self.limit;
+        let from = match self.after {
+            Some(ref after) => after.clone(),
+            None => {
+                let in_range = match self.end {
+                    Some(ref end) => self.start < *end,
+                    None => true,
+                };
+                if in_range {
+                    let start = &self.start;
+                    engine.get_raw(start, &mut |v| page.push((start.clone(), v.to_vec())));
+                }
+                self.start.clone()
+            }
+        };
+        if page.len() < limit {
+            let mut collect = |k: &[u8], v: &[u8]| {
+                page.push((k.to_vec(), v.to_vec()));
+                if page.len() < limit {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            };
+            match self.end {
+                Some(ref end) => engine.each_between_raw(&from, end, &mut collect),
+                None => engine.each_above_raw(&from, &mut collect),
+            }
+        }
+        if page.len() < limit {
+            self.done = true;
+        }
+        if let Some((k, _)) = page.last() {
+            self.after = Some(k.clone());
+        }
+        page
+    }
+
+    /// Number of pairs left to return, as reported by the engine's counters.
+    pub fn remaining(&self, engine: &KVEngine) -> i64 {
+        if self.done {
+            return 0;
+        }
+        let from = self.after.as_ref().unwrap_or(&self.start);
+        let mut n = match self.end {
+            Some(ref end) if from < end => engine.count_between_raw(from, end),
+            Some(_) => return 0,
+            None => engine.count_above_raw(from),
+        };
+        if self.after.is_none() && engine.exists_raw(&self.start) {
+            n += 1;
+        }
+        n
+    }
+
+    /// Serializes the cursor into an opaque continuation token.
+    pub fn token(&self) -> String {
+        let mut flags = 0;
+        if self.after.is_some() {
+            flags |= HAS_AFTER;
+        }
+        if self.end.is_some() {
+            flags |= HAS_END;
+        }
+        if self.done {
+            flags |= DONE;
+        }
+        let mut buf = vec![TOKEN_VERSION];
+        buf.extend_from_slice(&(self.limit as u32).to_be_bytes());
+        buf.push(flags);
+        push_field(&mut buf, &self.start);
+        if let Some(ref after) = self.after {
+            push_field(&mut buf, after);
+        }
+        if let Some(ref end) = self.end {
+            push_field(&mut buf, end);
+        }
+        to_hex(&buf)
+    }
+
+    /// Restores a cursor saved with `token`.
+    pub fn from_token(token: &str) -> Result<Cursor> {
+        Cursor::decode(token).ok_or_else(|| ErrorKind::InvalidToken(token.to_string()).into())
+    }
+
+    fn decode(token: &str) -> Option<Cursor> {
+        let bytes = from_hex(token)?;
+        if bytes.len() < 6 || bytes[0] != TOKEN_VERSION {
+            return None;
+        }
+        let limit = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+        let flags = bytes[5];
+        let mut rest = &bytes[6..];
+        let start = take_field(&mut rest)?.to_vec();
+        let after = if flags & HAS_AFTER != 0 {
+            Some(take_field(&mut rest)?.to_vec())
+        } else {
+            None
+        };
+        let end = if flags & HAS_END != 0 {
+            Some(take_field(&mut rest)?.to_vec())
+        } else {
+            None
+        };
+        if !rest.is_empty() || limit == 0 {
+            return None;
+        }
+        Some(Cursor {
+            limit,
+            start,
+            end,
+            after,
+            done: flags & DONE != 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_round_trip() {
+        let cursors = vec![
+            Cursor::new(10),
+            Cursor::new(3).from("b").until("k\0\u{ff}"),
+            Cursor {
+                limit: 7,
+                start: Vec::new(),
+                end: None,
+                after: Some(vec![0, 0xFF, b'x']),
+                done: true,
+            },
+        ];
+        for cursor in cursors {
+            assert_eq!(Cursor::from_token(&cursor.token()).unwrap(), cursor);
+        }
+    }
+
+    #[test]
+    fn bad_tokens() {
+        let token = Cursor::new(5).from("a").until("z").token();
+        assert!(Cursor::from_token(&token[..token.len() - 2]).is_err());
+        assert!(Cursor::from_token(&format!("{}00", token)).is_err());
+        assert!(Cursor::from_token(&format!("02{}", &token[2..])).is_err());
+        assert!(Cursor::from_token(&token.replacen("01", "0x", 1)).is_err());
+        assert!(Cursor::from_token("").is_err());
+        // A zero limit cannot come from `Cursor::new`.
+        assert!(Cursor::from_token("01000000000000000000").is_err());
+        match Cursor::from_token("xyz").unwrap_err().kind() {
+            ErrorKind::InvalidToken(t) => assert_eq!(t, "xyz"),
+            _ => panic!(),
+        }
+    }
+}
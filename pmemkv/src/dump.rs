@@ -0,0 +1,516 @@
+#[cfg(feature = "json")]
+use crate::cursor::{from_hex, to_hex};
+use crate::errors::*;
+use crate::kvengine::{KVEngine, RawEachFn};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::ControlFlow;
+
+const DUMP_MAGIC: &[u8] = b"PMKVDMP\x01";
+#[cfg(feature = "json")]
+const JSON_FORMAT: &str = "pmemkv-dump";
+#[cfg(feature = "json")]
+const JSON_VERSION: u64 = 1;
+const DEFAULT_PROGRESS_EVERY: u64 = 10_000;
+
+const TAG_ENTRY: u8 = 1;
+const TAG_END: u8 = 2;
+
+/// File format written by `Dump`. `Restore` recognizes both on its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    /// Length-prefixed records, each with a CRC-32C, behind a versioned header.
+    Binary,
+    /// One JSON object per line. Keys and values are strings when they are valid
+    /// UTF-8 and hex-encoded `key_hex` / `value_hex` fields otherwise.
+    #[cfg(feature = "json")]
+    JsonLines,
+}
+
+/// Pairs and key plus value bytes processed by a dump or restore so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DumpStats {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+type ProgressFn<'a> = dyn FnMut(&DumpStats) + 'a;
+
+// Calls the progress callback every `every` pairs and once at the end.
+struct Progress<'a> {
+    callback: Option<Box<ProgressFn<'a>>>,
+    every: u64,
+    pending: u64,
+}
+
+impl<'a> Progress<'a> {
+    fn new() -> Progress<'a> {
+        Progress {
+            callback: None,
+            every: DEFAULT_PROGRESS_EVERY,
+            pending: 0,
+        }
+    }
+
+    fn set(&mut self, every: u64, callback: Box<ProgressFn<'a>>) {
+        self.every = every.max(1);
+        self.callback = Some(callback);
+    }
+
+    fn entry(&mut self, stats: &DumpStats) {
+        self.pending += 1;
+        if self.pending == self.every {
+            self.pending = 0;
+            if let Some(ref mut callback) = self.callback {
+                callback(stats);
+            }
+        }
+    }
+
+    fn finish(&mut self, stats: &DumpStats) {
+        if let Some(ref mut callback) = self.callback {
+            callback(stats);
+        }
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    ErrorKind::InvalidFormat(format!("dump: {}", reason)).into()
+}
+
+fn in_range(key: &[u8], from: &[u8], until: Option<&[u8]>) -> bool {
+    key >= from
+        && match until {
+            Some(until) => key < until,
+            None => true,
+        }
+}
+
+// Binary record layout, as in the WAL: payload length and CRC-32C of the payload,
+// both little-endian u32, then the payload. An entry payload is the tag, the key
+// length as u32, the key and the value; the final record carries the entry count
+// as u64 so a truncated dump is detected.
+fn write_record<W: Write>(out: &mut W, payload: &[u8]) -> Result<()> {
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(&crc32c::crc32c(payload).to_le_bytes())?;
+    out.write_all(payload)?;
+    Ok(())
+}
+
+fn write_binary_entry<W: Write>(out: &mut W, buf: &mut Vec<u8>, k: &[u8], v: &[u8]) -> Result<()> {
+    buf.clear();
+    buf.push(TAG_ENTRY);
+    buf.extend_from_slice(&(k.len() as u32).to_le_bytes());
+    buf.extend_from_slice(k);
+    buf.extend_from_slice(v);
+    write_record(out, buf)
+}
+
+#[cfg(feature = "json")]
+pub(crate) fn json_field(
+    object: &mut serde_json::Map<String, serde_json::Value>,
+    name: &str,
+    bytes: &[u8],
+) {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => object.insert(name.to_string(), s.into()),
+        Err(_) => object.insert(format!("{}_hex", name), to_hex(bytes).into()),
+    };
+}
+
+#[cfg(feature = "json")]
+fn write_json_line<W: Write>(out: &mut W, value: &serde_json::Value) -> Result<()> {
+    serde_json::to_writer(&mut *out, value).map_err(std::io::Error::from)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Streams the pairs of an engine into a portable file that `Restore` loads back,
+/// into the same or any other engine.
+pub struct Dump<'a> {
+    engine: &'a KVEngine,
+    format: DumpFormat,
+    from: Vec<u8>,
+    until: Option<Vec<u8>>,
+    progress: Progress<'a>,
+}
+
+impl<'a> Dump<'a> {
+    pub fn new(engine: &'a KVEngine) -> Dump<'a> {
+        Dump {
+            engine,
+            format: DumpFormat::Binary,
+            from: Vec::new(),
+            until: None,
+            progress: Progress::new(),
+        }
+    }
+
+    pub fn format(mut self, format: DumpFormat) -> Dump<'a> {
+        self.format = format;
+        self
+    }
+
+    /// Only dumps keys from `key`, inclusive.
+    pub fn from(mut self, key: &str) -> Dump<'a> {
+        self.from = key.as_bytes().to_vec();
+        self
+    }
+
+    /// Only dumps keys before `key`.
+    pub fn until(mut self, key: &str) -> Dump<'a> {
+        self.until = Some(key.as_bytes().to_vec());
+        self
+    }
+
+    /// Calls `callback` every `every` pairs and once when the dump is complete.
+    pub fn progress<F>(mut self, every: u64, callback: F) -> Dump<'a>
+    where
+        F: FnMut(&DumpStats) + 'a,
+    {
+        self.progress.set(every, Box::new(callback));
+        self
+    }
+
+    // Visits the pairs in range. The engine's range scans exclude both bounds, so
+    // the lower bound itself is looked up separately, as in `Cursor`.
+    fn scan(&self, callback: &mut RawEachFn) {
+        let from = &self.from[..];
+        let until = self.until.as_deref();
+        if !in_range(from, from, until) {
+            return;
+        }
+        let mut first = ControlFlow::Continue(());
+        if !from.is_empty() {
+            self.engine
+                .get_raw(from, &mut |v| first = callback(from, v));
+        }
+        if first.is_break() {
+            return;
+        }
+        match (from.is_empty(), until) {
+            (true, None) => self.engine.each_raw(callback),
+            (true, Some(until)) => self.engine.each_below_raw(until, callback),
+            (false, None) => self.engine.each_above_raw(from, callback),
+            (false, Some(until)) => self.engine.each_between_raw(from, until, callback),
+        }
+    }
+
+    /// Writes the dump to `writer` and returns what was written.
+    pub fn write_to<W: Write>(mut self, writer: W) -> Result<DumpStats> {
+        let mut out = BufWriter::new(writer);
+        match self.format {
+            DumpFormat::Binary => out.write_all(DUMP_MAGIC)?,
+            #[cfg(feature = "json")]
+            DumpFormat::JsonLines => write_json_line(
+                &mut out,
+                &serde_json::json!({ "format": JSON_FORMAT, "version": JSON_VERSION }),
+            )?,
+        }
+        let mut stats = DumpStats::default();
+        let mut result = Ok(());
+        let mut progress = std::mem::replace(&mut self.progress, Progress::new());
+        let format = self.format;
+        let mut buf = Vec::new();
+        self.scan(&mut |k, v| {
+            let written = match format {
+                DumpFormat::Binary => write_binary_entry(&mut out, &mut buf, k, v),
+                #[cfg(feature = "json")]
+                DumpFormat::JsonLines => {
+                    let mut object = serde_json::Map::new();
+                    json_field(&mut object, "key", k);
+                    json_field(&mut object, "value", v);
+                    write_json_line(&mut out, &object.into())
+                }
+            };
+            if let Err(e) = written {
+                result = Err(e);
+                return ControlFlow::Break(());
+            }
+            stats.entries += 1;
+            stats.bytes += (k.len() + v.len()) as u64;
+            progress.entry(&stats);
+            ControlFlow::Continue(())
+        });
+        result?;
+        match format {
+            DumpFormat::Binary => {
+                let mut end = vec![TAG_END];
+                end.extend_from_slice(&stats.entries.to_le_bytes());
+                write_record(&mut out, &end)?;
+            }
+            #[cfg(feature = "json")]
+            DumpFormat::JsonLines => {
+                write_json_line(&mut out, &serde_json::json!({ "count": stats.entries }))?
+            }
+        }
+        out.flush()?;
+        progress.finish(&stats);
+        Ok(stats)
+    }
+}
+
+/// Loads a dump written by `Dump` through `put`. Pairs already in the engine are
+/// overwritten; pairs restored before a damaged record is found stay in place.
+pub struct Restore<'a> {
+    engine: &'a mut KVEngine,
+    from: Vec<u8>,
+    until: Option<Vec<u8>>,
+    progress: Progress<'a>,
+}
+
+impl<'a> Restore<'a> {
+    pub fn new(engine: &'a mut KVEngine) -> Restore<'a> {
+        Restore {
+            engine,
+            from: Vec::new(),
+            until: None,
+            progress: Progress::new(),
+        }
+    }
+
+    /// Only restores keys from `key`, inclusive. Other pairs are still verified.
+    pub fn from(mut self, key: &str) -> Restore<'a> {
+        self.from = key.as_bytes().to_vec();
+        self
+    }
+
+    /// Only restores keys before `key`.
+    pub fn until(mut self, key: &str) -> Restore<'a> {
+        self.until = Some(key.as_bytes().to_vec());
+        self
+    }
+
+    /// Calls `callback` every `every` restored pairs and once at the end.
+    pub fn progress<F>(mut self, every: u64, callback: F) -> Restore<'a>
+    where
+        F: FnMut(&DumpStats) + 'a,
+    {
+        self.progress.set(every, Box::new(callback));
+        self
+    }
+
+    fn put(&mut self, stats: &mut DumpStats, key: &[u8], value: &[u8]) -> Result<()> {
+        if !in_range(key, &self.from, self.until.as_deref()) {
+            return Ok(());
+        }
+        self.engine.put_raw(key, value)?;
+        stats.entries += 1;
+        stats.bytes += (key.len() + value.len()) as u64;
+        self.progress.entry(stats);
+        Ok(())
+    }
+
+    /// Reads a dump in either format from `reader` and returns what was restored.
+    pub fn read_from<R: Read>(mut self, reader: R) -> Result<DumpStats> {
+        let mut input = BufReader::new(reader);
+        let binary = {
+            let head = input.fill_buf()?;
+            if head.is_empty() {
+                return Err(invalid("empty input"));
+            }
+            head[0] == DUMP_MAGIC[0]
+        };
+        let stats = if binary {
+            self.read_binary(&mut input)?
+        } else {
+            self.read_json(&mut input)?
+        };
+        self.progress.finish(&stats);
+        Ok(stats)
+    }
+
+    fn read_binary<R: BufRead>(&mut self, input: &mut R) -> Result<DumpStats> {
+        let mut magic = [0u8; 8];
+        input
+            .read_exact(&mut magic)
+            .map_err(|_| invalid("truncated header"))?;
+        if magic[..7] != DUMP_MAGIC[..7] {
+            return Err(invalid("not a pmemkv dump"));
+        }
+        if magic != DUMP_MAGIC {
+            return Err(invalid(&format!("unsupported version {}", magic[7])));
+        }
+        let mut stats = DumpStats::default();
+        let mut seen = 0u64;
+        let mut payload = Vec::new();
+        loop {
+            let mut header = [0u8; 8];
+            input
+                .read_exact(&mut header)
+                .map_err(|_| invalid("truncated"))?;
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+            let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            payload.clear();
+            input.by_ref().take(len).read_to_end(&mut payload)?;
+            if payload.len() as u64 != len {
+                return Err(invalid("truncated"));
+            }
+            if crc32c::crc32c(&payload) != crc {
+                return Err(invalid(&format!(
+                    "checksum mismatch in record {}",
+                    seen + 1
+                )));
+            }
+            match payload.split_first() {
+                Some((&TAG_ENTRY, rest)) if rest.len() >= 4 => {
+                    let klen = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                    let rest = &rest[4..];
+                    if rest.len() < klen {
+                        return Err(invalid(&format!("bad record {}", seen + 1)));
+                    }
+                    let (key, value) = rest.split_at(klen);
+                    self.put(&mut stats, key, value)?;
+                    seen += 1;
+                }
+                Some((&TAG_END, rest)) if rest.len() == 8 => {
+                    let mut count = [0u8; 8];
+                    count.copy_from_slice(rest);
+                    if u64::from_le_bytes(count) != seen {
+                        return Err(invalid("entry count mismatch"));
+                    }
+                    return Ok(stats);
+                }
+                _ => return Err(invalid(&format!("bad record {}", seen + 1))),
+            }
+        }
+    }
+
+    #[cfg(feature = "json")]
+    fn read_json<R: BufRead>(&mut self, input: &mut R) -> Result<DumpStats> {
+        use serde_json::Value;
+
+        fn bytes(object: &serde_json::Map<String, Value>, name: &str) -> Option<Vec<u8>> {
+            match object.get(name) {
+                Some(Value::String(s)) => Some(s.as_bytes().to_vec()),
+                Some(_) => None,
+                None => match object.get(&format!("{}_hex", name)) {
+                    Some(Value::String(s)) => from_hex(s),
+                    _ => None,
+                },
+            }
+        }
+
+        let mut stats = DumpStats::default();
+        let mut seen = 0u64;
+        let mut header = true;
+        for (n, line) in input.lines().enumerate() {
+            let line = line?;
+            let bad = || invalid(&format!("bad line {}", n + 1));
+            let object = match serde_json::from_str(&line) {
+                Ok(Value::Object(object)) => object,
+                _ => return Err(bad()),
+            };
+            if header {
+                if object.get("format").and_then(Value::as_str) != Some(JSON_FORMAT) {
+                    return Err(invalid("not a pmemkv dump"));
+                }
+                match object.get("version").and_then(Value::as_u64) {
+                    Some(JSON_VERSION) => {}
+                    v => return Err(invalid(&format!("unsupported version {:?}", v))),
+                }
+                header = false;
+                continue;
+            }
+            if let Some(count) = object.get("count") {
+                if count.as_u64() != Some(seen) {
+                    return Err(invalid("entry count mismatch"));
+                }
+                return Ok(stats);
+            }
+            match (bytes(&object, "key"), bytes(&object, "value")) {
+                (Some(key), Some(value)) => self.put(&mut stats, &key, &value)?,
+                _ => return Err(bad()),
+            }
+            seen += 1;
+        }
+        Err(invalid("truncated"))
+    }
+
+    #[cfg(not(feature = "json"))]
+    fn read_json<R: BufRead>(&mut self, _input: &mut R) -> Result<DumpStats> {
+        Err(invalid("not a binary pmemkv dump"))
+    }
+}
+
+/// Dumps every pair of `engine` into `writer`.
+pub fn dump<W: Write>(engine: &KVEngine, writer: W, format: DumpFormat) -> Result<DumpStats> {
+    Dump::new(engine).format(format).write_to(writer)
+}
+
+/// Restores a dump from `reader` into `engine`.
+pub fn restore<R: Read>(engine: &mut KVEngine, reader: R) -> Result<DumpStats> {
+    Restore::new(engine).read_from(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine() -> KVEngine {
+        let config = r#"{"path":"/dev/shm","size":1073741824}"#;
+        KVEngine::start_string("vsmap", config, None::<fn(&str, &str, &str)>).unwrap()
+    }
+
+    fn filled() -> KVEngine {
+        let mut engine = engine();
+        engine.put_raw(b"a", b"1").unwrap();
+        engine.put_raw(b"b\xFF", b"\x00\xFE").unwrap();
+        engine.put_raw(b"c", b"").unwrap();
+        engine
+    }
+
+    fn pairs(engine: &KVEngine) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut pairs = Vec::new();
+        engine.each_raw(&mut |k, v| {
+            pairs.push((k.to_vec(), v.to_vec()));
+            ControlFlow::Continue(())
+        });
+        pairs
+    }
+
+    fn round_trip(format: DumpFormat) {
+        let source = filled();
+        let mut buf = Vec::new();
+        let written = dump(&source, &mut buf, format).unwrap();
+        assert_eq!(written.entries, 3);
+        let mut target = engine();
+        let read = restore(&mut target, &buf[..]).unwrap();
+        assert_eq!(read, written);
+        assert_eq!(pairs(&target), pairs(&source));
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        round_trip(DumpFormat::Binary);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trip() {
+        round_trip(DumpFormat::JsonLines);
+    }
+
+    #[test]
+    fn truncated_binary() {
+        let mut buf = Vec::new();
+        dump(&filled(), &mut buf, DumpFormat::Binary).unwrap();
+        for cut in DUMP_MAGIC.len()..buf.len() {
+            assert!(restore(&mut engine(), &buf[..cut]).is_err());
+        }
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(restore(&mut engine(), &buf[..]).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn truncated_json() {
+        let mut buf = Vec::new();
+        dump(&filled(), &mut buf, DumpFormat::JsonLines).unwrap();
+        let without_count = buf[..buf.len() - 1]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .unwrap();
+        assert!(restore(&mut engine(), &buf[..without_count + 1]).is_err());
+    }
+}
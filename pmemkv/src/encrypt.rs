@@ -0,0 +1,394 @@
+use crate::errors::*;
+use crate::kvengine::{EngineRef, KVEngine, SharedEngine};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::ChaCha20Poly1305;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::thread::{self, JoinHandle};
+
+const FORMAT_V1: u8 = 1;
+const TAG_AES_GCM: u8 = 1;
+const TAG_CHACHA: u8 = 2;
+const NONCE_LEN: usize = 12;
+// Format byte, cipher tag and key id.
+const HEADER_LEN: usize = 6;
+const KEY_AAD: &[u8] = b"pmemkv-key";
+
+/// A 256-bit encryption key.
+pub type Key = [u8; 32];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn tag(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => TAG_AES_GCM,
+            Cipher::ChaCha20Poly1305 => TAG_CHACHA,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Cipher> {
+        match tag {
+            TAG_AES_GCM => Some(Cipher::Aes256Gcm),
+            TAG_CHACHA => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    // Returns the random nonce followed by the ciphertext.
+    fn seal(self, key: &Key, aad: &[u8], msg: &[u8]) -> Option<Vec<u8>> {
+        let payload = Payload { msg, aad };
+        let (nonce, sealed) = match self {
+            Cipher::Aes256Gcm => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let sealed = Aes256Gcm::new(key.into()).encrypt(&nonce, payload);
+                (nonce.to_vec(), sealed.ok()?)
+            }
+            Cipher::ChaCha20Poly1305 => {
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let sealed = ChaCha20Poly1305::new(key.into()).encrypt(&nonce, payload);
+                (nonce.to_vec(), sealed.ok()?)
+            }
+        };
+        let mut buf = nonce;
+        buf.extend_from_slice(&sealed);
+        Some(buf)
+    }
+
+    fn open(self, key: &Key, aad: &[u8], body: &[u8]) -> Option<Vec<u8>> {
+        if body.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, msg) = body.split_at(NONCE_LEN);
+        let payload = Payload { msg, aad };
+        match self {
+            Cipher::Aes256Gcm => Aes256Gcm::new(key.into())
+                .decrypt(nonce.into(), payload)
+                .ok(),
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into())
+                .decrypt(nonce.into(), payload)
+                .ok(),
+        }
+    }
+}
+
+// Value layout: format byte, cipher tag, big-endian id of the key it was sealed
+// with, nonce, then ciphertext and tag. The plaintext key is the associated data, so
+// a value copied under another key fails to decrypt.
+fn key_id(stored: &[u8]) -> Option<u32> {
+    if stored.len() < HEADER_LEN || stored[0] != FORMAT_V1 {
+        return None;
+    }
+    Some(u32::from_be_bytes([
+        stored[2], stored[3], stored[4], stored[5],
+    ]))
+}
+
+struct Keyring {
+    current: u32,
+    keys: HashMap<u32, Key>,
+}
+
+/// Engine wrapper encrypting values at rest. Every value records the cipher and the id
+/// of the key it was sealed with, so keys can be rotated while old values remain
+/// readable. Engine accesses are serialized by a lock, so the wrapper can be shared with
+/// a background rotation.
+pub struct EncryptedEngine {
+    engine: SharedEngine,
+    cipher: Cipher,
+    // Key-encryption key for deterministic encryption of keys, if enabled.
+    key_key: Option<Key>,
+    keyring: RwLock<Keyring>,
+}
+
+/// Background re-encryption started by `EncryptedEngine::start_rotation`.
+pub struct RotationHandle {
+    thread: Option<JoinHandle<Result<usize>>>,
+}
+
+impl EncryptedEngine {
+    /// Wraps `engine`, sealing new values with `key`, known by `id`.
+    pub fn new(engine: KVEngine, id: u32, key: Key) -> EncryptedEngine {
+        let mut keys = HashMap::new();
+        keys.insert(id, key);
+        EncryptedEngine {
+            engine: SharedEngine::new(engine),
+            cipher: Cipher::Aes256Gcm,
+            key_key: None,
+            keyring: RwLock::new(Keyring { current: id, keys }),
+        }
+    }
+
+    pub fn cipher(mut self, cipher: Cipher) -> EncryptedEngine {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Registers a retired key so values sealed with it can still be read.
+    pub fn add_key(mut self, id: u32, key: Key) -> EncryptedEngine {
+        self.keyring
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys
+            .insert(id, key);
+        self
+    }
+
+    /// Also encrypts keys, deterministically with AES-GCM-SIV under `key`, so equal
+    /// keys map to the same stored key. Stored keys lose their order, so ranges and
+    /// ordered scans no longer apply; `key` cannot be rotated.
+    pub fn encrypt_keys(mut self, key: Key) -> EncryptedEngine {
+        self.key_key = Some(key);
+        self
+    }
+
+    /// Locks the engine for reads until the returned guard is dropped.
+    pub fn engine(&self) -> EngineRef<'_> {
+        self.engine.read()
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine.into_inner()
+    }
+
+    /// Id of the key new values are sealed with.
+    pub fn current_key(&self) -> u32 {
+        self.keyring().current
+    }
+
+    fn keyring(&self) -> RwLockReadGuard<'_, Keyring> {
+        self.keyring.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stored_key(&self, key: &[u8]) -> Result<Vec<u8>> {
+        match self.key_key {
+            Some(ref kek) => Aes256GcmSiv::new(kek.into())
+                .encrypt(
+                    &[0; NONCE_LEN].into(),
+                    Payload {
+                        msg: key,
+                        aad: KEY_AAD,
+                    },
+                )
+                .map_err(|_| ErrorKind::Fail.into()),
+            None => Ok(key.to_vec()),
+        }
+    }
+
+    fn plain_key(&self, stored: &[u8]) -> Option<Vec<u8>> {
+        match self.key_key {
+            Some(ref kek) => Aes256GcmSiv::new(kek.into())
+                .decrypt(
+                    &[0; NONCE_LEN].into(),
+                    Payload {
+                        msg: stored,
+                        aad: KEY_AAD,
+                    },
+                )
+                .ok(),
+            None => Some(stored.to_vec()),
+        }
+    }
+
+    fn seal(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let keyring = self.keyring();
+        let id = keyring.current;
+        let sealed = keyring
+            .keys
+            .get(&id)
+            .and_then(|k| self.cipher.seal(k, key, value));
+        let sealed = match sealed {
+            Some(sealed) => sealed,
+            None => bail!(ErrorKind::Fail),
+        };
+        let mut buf = Vec::with_capacity(HEADER_LEN + sealed.len());
+        buf.push(FORMAT_V1);
+        buf.push(self.cipher.tag());
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&sealed);
+        Ok(buf)
+    }
+
+    fn open(&self, key: &[u8], stored: &[u8]) -> Result<Vec<u8>> {
+        let keyring = self.keyring();
+        let opened = key_id(stored).and_then(|id| {
+            let cipher = Cipher::from_tag(stored[1])?;
+            cipher.open(keyring.keys.get(&id)?, key, &stored[HEADER_LEN..])
+        });
+        match opened {
+            Some(value) => Ok(value),
+            None => bail!(ErrorKind::InvalidFormat(
+                String::from_utf8_lossy(key).into_owned()
+            )),
+        }
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        let key = key.as_bytes();
+        let stored = self.stored_key(key)?;
+        // Sealed under the engine lock: a rotation scans for stale values under the
+        // same lock after installing its key, so it either sees this value or this
+        // value is sealed with the new key.
+        let mut engine = self.engine.lock();
+        let sealed = self.seal(key, value)?;
+        engine.put_raw(&stored, &sealed)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = key.as_bytes();
+        let stored = self.engine.lock().get_copy_raw(&self.stored_key(key)?);
+        match stored {
+            Some(stored) => self.open(key, &stored).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// False also when the key cannot be encrypted, as nothing can be stored under it.
+    pub fn exists(&self, key: &str) -> bool {
+        match self.stored_key(key.as_bytes()) {
+            Ok(stored) => self.engine.lock().exists_raw(&stored),
+            Err(_) => false,
+        }
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let stored = self.stored_key(key.as_bytes())?;
+        let result = self.engine.lock().remove_raw(&stored);
+        match result {
+            Err(Error(ErrorKind::NotFound(_), _)) => bail!(ErrorKind::NotFound(key.to_string())),
+            result => result,
+        }
+    }
+
+    pub fn count(&self) -> i64 {
+        self.engine.lock().count()
+    }
+
+    /// Visits every pair decrypted. Stops at the first entry that cannot be decrypted
+    /// and returns its error.
+    pub fn each<F>(&self, mut callback: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let mut result = Ok(());
+        self.engine.lock().each_raw(&mut |k, v| {
+            let opened = match self.plain_key(k) {
+                Some(key) => self.open(&key, v).map(|value| (key, value)),
+                None => {
+                    Err(ErrorKind::InvalidFormat(String::from_utf8_lossy(k).into_owned()).into())
+                }
+            };
+            match opened {
+                Ok((key, value)) => {
+                    callback(&key, &value);
+                    ControlFlow::Continue(())
+                }
+                Err(e) => {
+                    result = Err(e);
+                    ControlFlow::Break(())
+                }
+            }
+        });
+        result
+    }
+
+    fn install_key(&self, id: u32, key: Key) {
+        let mut keyring = self.keyring.write().unwrap_or_else(|e| e.into_inner());
+        keyring.keys.insert(id, key);
+        keyring.current = id;
+    }
+
+    /// Makes `key` the current key and re-encrypts every value sealed with another
+    /// one. Returns how many values were re-encrypted.
+    pub fn rotate_key(&self, id: u32, key: Key) -> Result<usize> {
+        self.install_key(id, key);
+        self.reencrypt()
+    }
+
+    /// Like `rotate_key`, but re-encrypts on a background thread. New writes use the
+    /// new key as soon as this returns.
+    pub fn start_rotation(db: &Arc<EncryptedEngine>, id: u32, key: Key) -> RotationHandle {
+        db.install_key(id, key);
+        let db = Arc::clone(db);
+        RotationHandle {
+            thread: Some(thread::spawn(move || db.reencrypt())),
+        }
+    }
+
+    fn reencrypt(&self) -> Result<usize> {
+        let current = self.current_key();
+        let mut stale = Vec::new();
+        self.engine.lock().each_raw(&mut |k, v| {
+            if key_id(v) != Some(current) {
+                stale.push(k.to_vec());
+            }
+            ControlFlow::Continue(())
+        });
+        let mut rotated = 0;
+        let mut failed = None;
+        for stored in stale {
+            let key = match self.plain_key(&stored) {
+                Some(key) => key,
+                None => {
+                    failed = Some(stored);
+                    continue;
+                }
+            };
+            // Under the engine lock, so a value written meanwhile is left alone.
+            self.engine.lock().update_raw(&stored, |value| {
+                let value = value?;
+                if key_id(value) == Some(self.current_key()) {
+                    return Some(value.to_vec());
+                }
+                match self.open(&key, value).and_then(|v| self.seal(&key, &v)) {
+                    Ok(sealed) => {
+                        rotated += 1;
+                        Some(sealed)
+                    }
+                    Err(_) => {
+                        failed = Some(key.clone());
+                        Some(value.to_vec())
+                    }
+                }
+            })?;
+        }
+        match failed {
+            Some(key) => bail!(ErrorKind::InvalidFormat(
+                String::from_utf8_lossy(&key).into_owned()
+            )),
+            None => Ok(rotated),
+        }
+    }
+}
+
+impl RotationHandle {
+    pub fn is_finished(&self) -> bool {
+        match self.thread {
+            Some(ref thread) => thread.is_finished(),
+            None => true,
+        }
+    }
+
+    /// Waits for the rotation and returns how many values it re-encrypted.
+    pub fn join(mut self) -> Result<usize> {
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            _ => bail!(ErrorKind::Fail),
+        }
+    }
+}
+
+impl Drop for RotationHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
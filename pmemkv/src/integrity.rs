@@ -0,0 +1,169 @@
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use std::ops::ControlFlow;
+
+const TAG_CRC32C: u8 = 1;
+#[cfg(feature = "xxhash")]
+const TAG_XXH64: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Checksum {
+    Crc32c,
+    #[cfg(feature = "xxhash")]
+    XxHash64,
+}
+
+/// Result of `IntegrityEngine::scrub`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScrubReport {
+    pub checked: u64,
+    /// Keys whose value failed verification, in scan order.
+    pub corrupted: Vec<Vec<u8>>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupted.is_empty()
+    }
+}
+
+// Value layout: the checksum tag, the little-endian checksum of the value (4 bytes
+// for CRC-32C, 8 for xxHash64), then the value itself.
+fn seal(checksum: Checksum, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(9 + value.len());
+    match checksum {
+        Checksum::Crc32c => {
+            buf.push(TAG_CRC32C);
+            buf.extend_from_slice(&crc32c::crc32c(value).to_le_bytes());
+        }
+        #[cfg(feature = "xxhash")]
+        Checksum::XxHash64 => {
+            buf.push(TAG_XXH64);
+            buf.extend_from_slice(&xxhash_rust::xxh64::xxh64(value, 0).to_le_bytes());
+        }
+    }
+    buf.extend_from_slice(value);
+    buf
+}
+
+pub(crate) fn verify(stored: &[u8]) -> Option<&[u8]> {
+    let (&tag, rest) = stored.split_first()?;
+    match tag {
+        TAG_CRC32C if rest.len() >= 4 => {
+            let (sum, value) = rest.split_at(4);
+            let sum = u32::from_le_bytes([sum[0], sum[1], sum[2], sum[3]]);
+            if crc32c::crc32c(value) == sum {
+                Some(value)
+            } else {
+                None
+            }
+        }
+        #[cfg(feature = "xxhash")]
+        TAG_XXH64 if rest.len() >= 8 => {
+            let (sum, value) = rest.split_at(8);
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(sum);
+            if xxhash_rust::xxh64::xxh64(value, 0) == u64::from_le_bytes(buf) {
+                Some(value)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn corrupted(key: &[u8]) -> Error {
+    ErrorKind::Corrupted(String::from_utf8_lossy(key).into_owned()).into()
+}
+
+/// Engine wrapper storing a checksum next to every value and verifying it on reads,
+/// so damaged values surface as `ErrorKind::Corrupted` instead of wrong data.
+pub struct IntegrityEngine {
+    engine: KVEngine,
+    checksum: Checksum,
+}
+
+impl IntegrityEngine {
+    pub fn new(engine: KVEngine) -> IntegrityEngine {
+        IntegrityEngine {
+            engine,
+            checksum: Checksum::Crc32c,
+        }
+    }
+
+    /// Checksum used for new writes. Reads verify values written with any of them.
+    pub fn checksum(mut self, checksum: Checksum) -> IntegrityEngine {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn engine(&self) -> &KVEngine {
+        &self.engine
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine
+    }
+
+    pub fn put(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.engine
+            .put_raw(key.as_bytes(), &seal(self.checksum, value))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = key.as_bytes();
+        match self.engine.get_copy_raw(key) {
+            Some(stored) => match verify(&stored) {
+                Some(value) => Ok(Some(value.to_vec())),
+                None => Err(corrupted(key)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.engine.exists_raw(key.as_bytes())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.engine.remove_raw(key.as_bytes())
+    }
+
+    pub fn count(&self) -> i64 {
+        self.engine.count()
+    }
+
+    /// Visits every pair after verifying it. Stops at the first damaged value and
+    /// returns `ErrorKind::Corrupted`; use `scrub` to find all of them.
+    pub fn each<F>(&self, mut callback: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let mut result = Ok(());
+        self.engine.each_raw(&mut |k, v| match verify(v) {
+            Some(value) => {
+                callback(k, value);
+                ControlFlow::Continue(())
+            }
+            None => {
+                result = Err(corrupted(k));
+                ControlFlow::Break(())
+            }
+        });
+        result
+    }
+
+    /// Verifies every value in the engine and reports the damaged ones.
+    pub fn scrub(&self) -> ScrubReport {
+        let mut report = ScrubReport::default();
+        self.engine.each_raw(&mut |k, v| {
+            report.checked += 1;
+            if verify(v).is_none() {
+                report.corrupted.push(k.to_vec());
+            }
+            ControlFlow::Continue(())
+        });
+        report
+    }
+}
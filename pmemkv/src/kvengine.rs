@@ -1,13 +1,20 @@
 use crate::errors::*;
 use pmemkv_sys::KVEngine as KVEngineSys;
 use pmemkv_sys::*;
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
+use std::ops::{ControlFlow, Deref};
 use std::os::raw::{c_char, c_int, c_void};
 use std::slice;
+use std::sync::{Mutex, MutexGuard};
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct KVEngine(*mut KVEngineSys);
 
+// The handle owns the engine and may move to another thread. It is not `Sync`: most
+// engines do not support concurrent access, see `ConcurrentEngine` for those that do.
+unsafe impl Send for KVEngine {}
+
 impl Drop for KVEngine {
     fn drop(&mut self) {
         unsafe {
@@ -19,6 +26,21 @@ impl Drop for KVEngine {
 type EachFn = Fn(&[c_char], &[c_char]);
 type StartFn = Fn(*const c_char, *const c_char, *const c_char);
 type StartStringFn = Fn(&str, &str, &str);
+type RawGetFn<'a> = dyn FnMut(&[u8]) + 'a;
+pub(crate) type RawAllFn<'a> = dyn FnMut(&[u8]) -> ControlFlow<()> + 'a;
+pub(crate) type RawEachFn<'a> = dyn FnMut(&[u8], &[u8]) -> ControlFlow<()> + 'a;
+
+thread_local! {
+    static COPY_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+// Context handed to the raw scan trampolines. The C layer cannot abort a scan, so once
+// the callback breaks the remaining entries are skipped without being sliced or
+// forwarded.
+struct ScanState<'a, F: ?Sized> {
+    callback: &'a mut F,
+    stopped: bool,
+}
 
 extern "C" fn cb_wrapper<F>(closure: *mut c_void, bytes: c_int, v: *const c_char)
 where
@@ -112,6 +134,41 @@ extern "C" fn cb_each_string_wrapper<F>(
     opt_closure(k_s, v_s)
 }
 
+extern "C" fn cb_raw_wrapper(closure: *mut c_void, bytes: c_int, v: *const c_char) {
+    let opt_closure: &mut &mut RawGetFn = unsafe { &mut *(closure as *mut &mut RawGetFn) };
+    let slice = unsafe { slice::from_raw_parts(v as *const u8, bytes as usize) };
+    opt_closure(slice);
+}
+
+extern "C" fn cb_all_raw_wrapper(closure: *mut c_void, kb: c_int, k: *const c_char) {
+    let state = unsafe { &mut *(closure as *mut ScanState<RawAllFn>) };
+    if state.stopped {
+        return;
+    }
+    let ks = unsafe { slice::from_raw_parts(k as *const u8, kb as usize) };
+    state.stopped = (state.callback)(ks).is_break();
+}
+
+extern "C" fn cb_each_raw_wrapper(
+    closure: *mut c_void,
+    kb: c_int,
+    k: *const c_char,
+    vb: c_int,
+    v: *const c_char,
+) {
+    let state = unsafe { &mut *(closure as *mut ScanState<RawEachFn>) };
+    if state.stopped {
+        return;
+    }
+    let (ks, vs) = unsafe {
+        (
+            slice::from_raw_parts(k as *const u8, kb as usize),
+            slice::from_raw_parts(v as *const u8, vb as usize),
+        )
+    };
+    state.stopped = (state.callback)(ks, vs).is_break();
+}
+
 impl KVEngine {
     pub fn start<F>(engine: &str, config: &str, callback: Option<F>) -> Result<KVEngine>
     where
@@ -181,45 +238,20 @@ impl KVEngine {
         }
     }
 
+    // Point operations pass keys and values with explicit lengths, so they need no
+    // `CString` copies; only `get_copy` goes through a scratch buffer.
     pub fn put(&mut self, key: &str, value: &str) -> Result<()> {
-        let key_str = CString::new(key)?;
-        let val_str = CString::new(value)?;
-        let res = unsafe {
-            kvengine_put(
-                self.0,
-                key_str.to_bytes().len() as i32,
-                key_str.as_ptr(),
-                val_str.to_bytes().len() as i32,
-                val_str.as_ptr(),
-            )
-        };
-        if res == 1 {
-            Ok(())
-        } else if res == -1 {
-            Err(ErrorKind::Fail.into())
-        } else {
-            Err(ErrorKind::NotFound(key.to_string()).into())
-        }
+        self.put_raw(key.as_bytes(), value.as_bytes())
     }
 
     pub fn remove(&mut self, key: &str) -> Result<()> {
-        let key_str = CString::new(key)?;
-        let res =
-            unsafe { kvengine_remove(self.0, key_str.to_bytes().len() as i32, key_str.as_ptr()) };
-        if res == 1 {
-            Ok(())
-        } else if res == -1 {
-            Err(ErrorKind::Fail.into())
-        } else {
-            Err(ErrorKind::NotFound(key.to_string()).into())
-        }
+        self.remove_raw(key.as_bytes())
     }
 
     pub fn get<F>(&self, key: &str, callback: Option<F>) -> Result<()>
     where
         F: Fn(&[c_char]),
     {
-        let key_str = CString::new(key)?;
         match callback {
             Some(f) => unsafe {
                 let mut cb: &Fn(&[c_char]) = &f;
@@ -227,8 +259,8 @@ impl KVEngine {
                 kvengine_get(
                     self.0,
                     cb as *mut _ as *mut c_void,
-                    key_str.to_bytes().len() as i32,
-                    key_str.as_ptr(),
+                    key.len() as i32,
+                    key.as_ptr() as *const c_char,
                     Some(cb_wrapper::<F>),
                 )
             },
@@ -236,8 +268,8 @@ impl KVEngine {
                 kvengine_get(
                     self.0,
                     ::std::ptr::null_mut(),
-                    key_str.to_bytes().len() as i32,
-                    key_str.as_ptr(),
+                    key.len() as i32,
+                    key.as_ptr() as *const c_char,
                     None,
                 )
             },
@@ -249,7 +281,6 @@ impl KVEngine {
     where
         F: Fn(&str),
     {
-        let key_str = CString::new(key)?;
         match callback {
             Some(f) => unsafe {
                 let mut cb: &Fn(&str) = &f;
@@ -257,8 +288,8 @@ impl KVEngine {
                 kvengine_get(
                     self.0,
                     cb as *mut _ as *mut c_void,
-                    key_str.to_bytes().len() as i32,
-                    key_str.as_ptr(),
+                    key.len() as i32,
+                    key.as_ptr() as *const c_char,
                     Some(cb_string_wrapper::<F>),
                 )
             },
@@ -266,8 +297,8 @@ impl KVEngine {
                 kvengine_get(
                     self.0,
                     ::std::ptr::null_mut(),
-                    key_str.to_bytes().len() as i32,
-                    key_str.as_ptr(),
+                    key.len() as i32,
+                    key.as_ptr() as *const c_char,
                     None,
                 )
             },
@@ -275,42 +306,89 @@ impl KVEngine {
         Ok(())
     }
 
+    /// Copies the value into a per-thread buffer of `max_value_bytes`, reused across
+    /// calls, and returns it as a string. Fails if `max_value_bytes` is not positive.
     pub fn get_copy(&self, key: &str, max_value_bytes: i32) -> Result<String> {
-        let key_str = CString::new(key)?;
-        let val_vec = Vec::with_capacity(max_value_bytes as usize);
-        let res = unsafe {
-            kvengine_get_copy(
-                self.0,
-                key_str.to_bytes().len() as i32,
-                key_str.as_ptr(),
-                max_value_bytes,
-                val_vec.as_ptr() as *mut _,
-            )
-        };
-        if res == 1 {
-            Ok(unsafe {
-                CStr::from_ptr(val_vec.as_ptr())
-                    .to_string_lossy()
-                    .into_owned()
-            })
-        } else if res == -1 {
-            Err(ErrorKind::Fail.into())
-        } else {
-            Err(ErrorKind::NotFound(key.to_string()).into())
+        if max_value_bytes <= 0 {
+            bail!(ErrorKind::Fail);
         }
+        let max = max_value_bytes as usize;
+        COPY_BUFFER.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            // Kept initialized across calls, so only growing it costs a fill.
+            if buf.len() < max {
+                buf.resize(max, 0);
+            }
+            let res = unsafe {
+                kvengine_get_copy(
+                    self.0,
+                    key.len() as i32,
+                    key.as_ptr() as *const c_char,
+                    max_value_bytes,
+                    buf.as_mut_ptr() as *mut c_char,
+                )
+            };
+            status_raw(res, key.as_bytes())?;
+            match CStr::from_bytes_until_nul(&buf[..max]) {
+                Ok(value) => Ok(value.to_string_lossy().into_owned()),
+                Err(_) => bail!(ErrorKind::InvalidFormat(key.to_string())),
+            }
+        })
+    }
+
+    /// Replaces the contents of `buf` with the value of `key`, reusing its allocation.
+    /// Fails with `NotFound`, leaving `buf` empty, if the key does not exist.
+    pub fn get_into(&self, key: &str, buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        let mut found = false;
+        self.get_raw(key.as_bytes(), &mut |v| {
+            buf.extend_from_slice(v);
+            found = true;
+        });
+        if !found {
+            bail!(ErrorKind::NotFound(key.to_string()));
+        }
+        Ok(())
     }
 
     pub fn exists(&self, key: &str) -> Result<()> {
-        let key_str = CString::new(key)?;
-        let res =
-            unsafe { kvengine_exists(self.0, key_str.to_bytes().len() as i32, key_str.as_ptr()) };
-        if res == 1 {
-            Ok(())
-        } else if res == -1 {
-            Err(ErrorKind::Fail.into())
-        } else {
-            Err(ErrorKind::NotFound(key.to_string()).into())
+        self.exists_bytes(key.as_bytes())
+    }
+
+    /// Like `put`, for keys and values that are arbitrary bytes.
+    pub fn put_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_raw(key, value)
+    }
+
+    /// Like `remove`, for keys that are arbitrary bytes.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.remove_raw(key)
+    }
+
+    /// Calls `callback` with the value of `key`. Fails with `NotFound` if the key does
+    /// not exist.
+    pub fn get_bytes<F>(&self, key: &[u8], mut callback: F) -> Result<()>
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut found = false;
+        self.get_raw(key, &mut |v| {
+            callback(v);
+            found = true;
+        });
+        if !found {
+            bail!(ErrorKind::NotFound(
+                String::from_utf8_lossy(key).into_owned()
+            ));
         }
+        Ok(())
+    }
+
+    /// Like `exists`, for keys that are arbitrary bytes.
+    pub fn exists_bytes(&self, key: &[u8]) -> Result<()> {
+        let res =
+            unsafe { kvengine_exists(self.0, key.len() as i32, key.as_ptr() as *const c_char) };
+        status_raw(res, key)
     }
 
     pub fn each<F>(&self, callback: Option<F>)
@@ -832,3 +910,253 @@ impl KVEngine {
         }
     }
 }
+
+fn status_raw(res: i8, key: &[u8]) -> Result<()> {
+    if res == 1 {
+        Ok(())
+    } else if res == -1 {
+        Err(ErrorKind::Fail.into())
+    } else {
+        Err(ErrorKind::NotFound(String::from_utf8_lossy(key).into_owned()).into())
+    }
+}
+
+// Byte-level building blocks for the helpers layered on top of the engine. Keys are
+// passed with explicit lengths, so they do not need to be valid C strings.
+impl KVEngine {
+    pub(crate) fn put_raw(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        unsafe { self.put_shared(key, value) }
+    }
+
+    pub(crate) fn remove_raw(&mut self, key: &[u8]) -> Result<()> {
+        unsafe { self.remove_shared(key) }
+    }
+
+    // Writers through a shared handle. The caller guarantees that the engine supports
+    // concurrent writes, or that nothing else accesses it meanwhile.
+    pub(crate) unsafe fn put_shared(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let res = kvengine_put(
+            self.0,
+            key.len() as i32,
+            key.as_ptr() as *const c_char,
+            value.len() as i32,
+            value.as_ptr() as *const c_char,
+        );
+        status_raw(res, key)
+    }
+
+    pub(crate) unsafe fn remove_shared(&self, key: &[u8]) -> Result<()> {
+        let res = kvengine_remove(self.0, key.len() as i32, key.as_ptr() as *const c_char);
+        status_raw(res, key)
+    }
+
+    pub(crate) fn get_raw(&self, key: &[u8], callback: &mut RawGetFn) {
+        let mut cb: &mut RawGetFn = callback;
+        let cb = &mut cb;
+        unsafe {
+            kvengine_get(
+                self.0,
+                cb as *mut _ as *mut c_void,
+                key.len() as i32,
+                key.as_ptr() as *const c_char,
+                Some(cb_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn all_raw(&self, callback: &mut RawAllFn) {
+        let mut state = ScanState {
+            callback,
+            stopped: false,
+        };
+        unsafe {
+            kvengine_all(
+                self.0,
+                &mut state as *mut _ as *mut c_void,
+                Some(cb_all_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn all_above_raw(&self, key: &[u8], callback: &mut RawAllFn) {
+        let mut state = ScanState {
+            callback,
+            stopped: false,
+        };
+        unsafe {
+            kvengine_all_above(
+                self.0,
+                &mut state as *mut _ as *mut c_void,
+                key.len() as i32,
+                key.as_ptr() as *const c_char,
+                Some(cb_all_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn all_below_raw(&self, key: &[u8], callback: &mut RawAllFn) {
+        let mut state = ScanState {
+            callback,
+            stopped: false,
+        };
+        unsafe {
+            kvengine_all_below(
+                self.0,
+                &mut state as *mut _ as *mut c_void,
+                key.len() as i32,
+                key.as_ptr() as *const c_char,
+                Some(cb_all_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn all_between_raw(&self, key1: &[u8], key2: &[u8], callback: &mut RawAllFn) {
+        let mut state = ScanState {
+            callback,
+            stopped: false,
+        };
+        unsafe {
+            kvengine_all_between(
+                self.0,
+                &mut state as *mut _ as *mut c_void,
+                key1.len() as i32,
+                key1.as_ptr() as *const c_char,
+                key2.len() as i32,
+                key2.as_ptr() as *const c_char,
+                Some(cb_all_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn each_raw(&self, callback: &mut RawEachFn) {
+        let mut state = ScanState {
+            callback,
+            stopped: false,
+        };
+        unsafe {
+            kvengine_each(
+                self.0,
+                &mut state as *mut _ as *mut c_void,
+                Some(cb_each_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn each_above_raw(&self, key: &[u8], callback: &mut RawEachFn) {
+        let mut state = ScanState {
+            callback,
+            stopped: false,
+        };
+        unsafe {
+            kvengine_each_above(
+                self.0,
+                &mut state as *mut _ as *mut c_void,
+                key.len() as i32,
+                key.as_ptr() as *const c_char,
+                Some(cb_each_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn each_below_raw(&self, key: &[u8], callback: &mut RawEachFn) {
+        let mut state = ScanState {
+            callback,
+            stopped: false,
+        };
+        unsafe {
+            kvengine_each_below(
+                self.0,
+                &mut state as *mut _ as *mut c_void,
+                key.len() as i32,
+                key.as_ptr() as *const c_char,
+                Some(cb_each_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn each_between_raw(&self, key1: &[u8], key2: &[u8], callback: &mut RawEachFn) {
+        let mut state = ScanState {
+            callback,
+            stopped: false,
+        };
+        unsafe {
+            kvengine_each_between(
+                self.0,
+                &mut state as *mut _ as *mut c_void,
+                key1.len() as i32,
+                key1.as_ptr() as *const c_char,
+                key2.len() as i32,
+                key2.as_ptr() as *const c_char,
+                Some(cb_each_raw_wrapper),
+            )
+        }
+    }
+
+    pub(crate) fn get_copy_raw(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut value = None;
+        self.get_raw(key, &mut |v| value = Some(v.to_vec()));
+        value
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const KVEngineSys {
+        self.0
+    }
+
+    pub(crate) fn exists_raw(&self, key: &[u8]) -> bool {
+        unsafe { kvengine_exists(self.0, key.len() as i32, key.as_ptr() as *const c_char) == 1 }
+    }
+
+    pub(crate) fn count_above_raw(&self, key: &[u8]) -> i64 {
+        unsafe { kvengine_count_above(self.0, key.len() as i32, key.as_ptr() as *const c_char) }
+    }
+
+    pub(crate) fn count_below_raw(&self, key: &[u8]) -> i64 {
+        unsafe { kvengine_count_below(self.0, key.len() as i32, key.as_ptr() as *const c_char) }
+    }
+
+    pub(crate) fn count_between_raw(&self, key1: &[u8], key2: &[u8]) -> i64 {
+        unsafe {
+            kvengine_count_between(
+                self.0,
+                key1.len() as i32,
+                key1.as_ptr() as *const c_char,
+                key2.len() as i32,
+                key2.as_ptr() as *const c_char,
+            )
+        }
+    }
+}
+
+/// Read access to the engine of a wrapper. Holds the wrapper's engine lock until it is
+/// dropped.
+pub struct EngineRef<'a>(MutexGuard<'a, KVEngine>);
+
+impl<'a> Deref for EngineRef<'a> {
+    type Target = KVEngine;
+
+    fn deref(&self) -> &KVEngine {
+        &self.0
+    }
+}
+
+// Engine owned by a wrapper that is shared between threads. Every access takes the
+// lock, since the engine itself may not support concurrent use.
+pub(crate) struct SharedEngine(Mutex<KVEngine>);
+
+impl SharedEngine {
+    pub(crate) fn new(engine: KVEngine) -> SharedEngine {
+        SharedEngine(Mutex::new(engine))
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, KVEngine> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn read(&self) -> EngineRef<'_> {
+        EngineRef(self.lock())
+    }
+
+    pub(crate) fn into_inner(self) -> KVEngine {
+        self.0.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -2,7 +2,30 @@ extern crate pmemkv_sys;
 #[macro_use]
 extern crate error_chain;
 
+pub mod batch;
+pub mod cache;
+pub mod compress;
+pub mod concurrent;
+pub mod cursor;
+pub mod dump;
+#[cfg(feature = "encryption")]
+pub mod encrypt;
+pub mod integrity;
 pub mod kvengine;
+pub mod merge;
+pub mod migrate;
+pub mod mvcc;
+pub mod range;
+pub mod scan;
+mod sequence;
+pub mod shard;
+pub mod store;
+pub mod tier;
+pub mod ttl;
+pub mod txn;
+pub mod update;
+pub mod verify;
+pub mod wal;
 
 pub mod errors {
     error_chain! {
@@ -14,10 +37,41 @@ pub mod errors {
             }
             #[derive(partial_eq)]
             Fail
+            #[derive(partial_eq)]
+            InvalidToken(t: String) {
+                description("InvalidToken"),
+                display("Invalid continuation token: {}", t),
+            }
+            #[derive(partial_eq)]
+            NotConcurrent(e: String) {
+                description("NotConcurrent"),
+                display("Engine does not support concurrent access: {}", e),
+            }
+            #[derive(partial_eq)]
+            Conflict(k: String) {
+                description("Conflict"),
+                display("Transaction conflict on: {}", k),
+            }
+            #[derive(partial_eq)]
+            Corrupted(key: String) {
+                description("Corrupted"),
+                display("Checksum mismatch for: {}", key),
+            }
+            #[derive(partial_eq)]
+            InvalidFormat(f: String) {
+                description("InvalidFormat"),
+                display("Invalid or damaged data: {}", f),
+            }
+            #[derive(partial_eq)]
+            Unordered {
+                description("Unordered"),
+                display("Engine does not keep keys in order"),
+            }
         }
 
         foreign_links {
             Ffi(::std::ffi::NulError);
+            Io(::std::io::Error);
         }
     }
 }
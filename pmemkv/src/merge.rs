@@ -0,0 +1,377 @@
+use crate::batch::WriteBatch;
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use crate::range::Entry;
+use crate::sequence;
+use std::cmp;
+use std::collections::BTreeSet;
+use std::ops::ControlFlow;
+
+/// Keys reserved for operands waiting to be merged in `MergeMode::Lazy`.
+pub const OPERAND_PREFIX: &[u8] = b"\xff\xfdpmemkv-merge:";
+
+/// Associative update applied by `MergeEngine::merge`. `existing` is the current
+/// value, or `None` if the key is absent.
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+/// Adds operands to the stored value. Both are 8-byte little-endian `u64`s; malformed
+/// values count as zero.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64Add;
+
+/// Keeps the larger of the stored value and the operand, comparing bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Max;
+
+/// Appends operands to the stored value, separated by `delimiter`.
+#[derive(Debug, Clone, Default)]
+pub struct StringAppend {
+    pub delimiter: Vec<u8>,
+}
+
+/// Treats values and operands as sets of members separated by `delimiter` and stores
+/// their sorted union.
+#[derive(Debug, Clone, Copy)]
+pub struct SetUnion {
+    pub delimiter: u8,
+}
+
+fn decode_u64(v: &[u8]) -> u64 {
+    if v.len() == 8 {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(v);
+        u64::from_le_bytes(buf)
+    } else {
+        0
+    }
+}
+
+impl MergeOperator for U64Add {
+    fn name(&self) -> &'static str {
+        "u64add"
+    }
+
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        let sum = existing
+            .map_or(0, decode_u64)
+            .wrapping_add(decode_u64(operand));
+        sum.to_le_bytes().to_vec()
+    }
+}
+
+impl MergeOperator for Max {
+    fn name(&self) -> &'static str {
+        "max"
+    }
+
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        match existing {
+            Some(e) => cmp::max(e, operand).to_vec(),
+            None => operand.to_vec(),
+        }
+    }
+}
+
+impl StringAppend {
+    pub fn new(delimiter: &str) -> StringAppend {
+        StringAppend {
+            delimiter: delimiter.as_bytes().to_vec(),
+        }
+    }
+}
+
+impl MergeOperator for StringAppend {
+    fn name(&self) -> &'static str {
+        "stringappend"
+    }
+
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        match existing {
+            Some(e) => {
+                let mut v = Vec::with_capacity(e.len() + self.delimiter.len() + operand.len());
+                v.extend_from_slice(e);
+                v.extend_from_slice(&self.delimiter);
+                v.extend_from_slice(operand);
+                v
+            }
+            None => operand.to_vec(),
+        }
+    }
+}
+
+impl SetUnion {
+    pub fn new(delimiter: u8) -> SetUnion {
+        SetUnion { delimiter }
+    }
+}
+
+impl MergeOperator for SetUnion {
+    fn name(&self) -> &'static str {
+        "setunion"
+    }
+
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        let delimiter = self.delimiter;
+        let members: BTreeSet<&[u8]> = existing
+            .unwrap_or_default()
+            .split(|b| *b == delimiter)
+            .chain(operand.split(|b| *b == delimiter))
+            .filter(|m| !m.is_empty())
+            .collect();
+        let mut v = Vec::new();
+        for (i, m) in members.iter().enumerate() {
+            if i > 0 {
+                v.push(delimiter);
+            }
+            v.extend_from_slice(m);
+        }
+        v
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeMode {
+    /// `merge` reads the current value and writes the merged result right away.
+    Eager,
+    /// `merge` only records the operand; it is applied when the key is read and
+    /// folded into the stored value by `fold`. Requires an ordered engine.
+    Lazy,
+}
+
+/// Engine wrapper that owns a `KVEngine` and the merge operator registered with it.
+/// Its writes are journaled with `WriteBatch::apply_atomic`, so run
+/// `WriteBatch::recover` on the engine after reopening it.
+pub struct MergeEngine {
+    engine: KVEngine,
+    operator: Box<dyn MergeOperator>,
+    mode: MergeMode,
+}
+
+fn operand_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = OPERAND_PREFIX.to_vec();
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+impl MergeEngine {
+    pub fn new<M>(engine: KVEngine, operator: M) -> MergeEngine
+    where
+        M: MergeOperator + 'static,
+    {
+        MergeEngine {
+            engine,
+            operator: Box::new(operator),
+            mode: MergeMode::Eager,
+        }
+    }
+
+    pub fn mode(mut self, mode: MergeMode) -> MergeEngine {
+        self.mode = mode;
+        self
+    }
+
+    pub fn operator(&self) -> &dyn MergeOperator {
+        self.operator.as_ref()
+    }
+
+    pub fn engine(&self) -> &KVEngine {
+        &self.engine
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine
+    }
+
+    // Pending operand keys and values for `key`, oldest first.
+    fn operands(&self, key: &[u8]) -> Vec<Entry> {
+        let mut operands = Vec::new();
+        if self.mode == MergeMode::Lazy {
+            self.engine
+                .each_prefix_raw(&operand_prefix(key), &mut |k, v| {
+                    operands.push((k.to_vec(), v.to_vec()));
+                    ControlFlow::Continue(())
+                });
+        }
+        operands
+    }
+
+    fn resolve(&self, key: &[u8]) -> (Option<Vec<u8>>, Vec<Entry>) {
+        let base = self.engine.get_copy_raw(key);
+        let operands = self.operands(key);
+        let value = operands.iter().fold(base, |acc, (_, operand)| {
+            Some(self.operator.merge(key, acc.as_deref(), operand))
+        });
+        (value, operands)
+    }
+
+    /// Returns the value of `key` with any pending operands applied.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.resolve(key.as_bytes()).0)
+    }
+
+    /// Overwrites `key`, discarding operands that are still pending for it.
+    pub fn put(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_bytes(key.as_bytes(), value);
+        for (k, _) in self.operands(key.as_bytes()) {
+            batch.remove_bytes(&k);
+        }
+        self.apply(batch)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        let key = key.as_bytes();
+        let operands = self.operands(key);
+        let exists = self.engine.exists_raw(key);
+        if !exists && operands.is_empty() {
+            bail!(ErrorKind::NotFound(
+                String::from_utf8_lossy(key).into_owned()
+            ));
+        }
+        let mut batch = WriteBatch::new();
+        if exists {
+            batch.remove_bytes(key);
+        }
+        for (k, _) in operands {
+            batch.remove_bytes(&k);
+        }
+        self.apply(batch)
+    }
+
+    /// Merges `operand` into the value of `key` with the registered operator.
+    pub fn merge(&mut self, key: &str, operand: &[u8]) -> Result<()> {
+        match self.mode {
+            MergeMode::Eager => {
+                let operator = &self.operator;
+                let key = key.as_bytes();
+                self.engine
+                    .update_raw(key, |existing| Some(operator.merge(key, existing, operand)))
+                    .map(|_| ())
+            }
+            MergeMode::Lazy => {
+                let mut operand_key = operand_prefix(key.as_bytes());
+                operand_key.extend_from_slice(&sequence::next().to_be_bytes());
+                self.engine.put_raw(&operand_key, operand)
+            }
+        }
+    }
+
+    /// Applies the pending operands of `key` and stores the result as its value.
+    /// Returns the number of operands folded.
+    pub fn fold(&mut self, key: &str) -> Result<usize> {
+        self.fold_raw(key.as_bytes())
+    }
+
+    fn fold_raw(&mut self, key: &[u8]) -> Result<usize> {
+        let (value, operands) = self.resolve(key);
+        if operands.is_empty() {
+            return Ok(0);
+        }
+        let mut batch = WriteBatch::new();
+        if let Some(ref v) = value {
+            batch.put_bytes(key, v);
+        }
+        for (k, _) in &operands {
+            batch.remove_bytes(k);
+        }
+        self.apply(batch)?;
+        Ok(operands.len())
+    }
+
+    /// Folds the pending operands of every key. Returns the number of keys updated.
+    pub fn fold_all(&mut self) -> Result<usize> {
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        self.engine.each_prefix_raw(OPERAND_PREFIX, &mut |k, _| {
+            let rest = &k[OPERAND_PREFIX.len()..];
+            if rest.len() >= 4 {
+                let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                if let Some(key) = rest.get(4..4 + len) {
+                    if keys.last().map(Vec::as_slice) != Some(key) {
+                        keys.push(key.to_vec());
+                    }
+                }
+            }
+            ControlFlow::Continue(())
+        });
+        for key in &keys {
+            self.fold_raw(key)?;
+        }
+        Ok(keys.len())
+    }
+
+    // The writes of put/remove/fold go through a crash-atomic batch so the base value
+    // and its operands never disagree after a restart. A single write needs no journal.
+    fn apply(&mut self, batch: WriteBatch) -> Result<()> {
+        let statuses = if batch.len() == 1 {
+            batch.apply(&mut self.engine)
+        } else {
+            batch.apply_atomic(&mut self.engine)?
+        };
+        for status in statuses {
+            match status {
+                Err(Error(ErrorKind::NotFound(_), _)) | Ok(()) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fold(op: &dyn MergeOperator, operands: &[&[u8]]) -> Option<Vec<u8>> {
+        operands.iter().fold(None, |existing, operand| {
+            Some(op.merge(b"key", existing.as_deref(), operand))
+        })
+    }
+
+    #[test]
+    fn u64_add() {
+        let one = 1u64.to_le_bytes();
+        let two = 2u64.to_le_bytes();
+        let max = u64::MAX.to_le_bytes();
+        assert_eq!(
+            fold(&U64Add, &[&one, &two]),
+            Some(3u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(fold(&U64Add, &[&max, &two]), Some(one.to_vec()));
+        // Malformed values and operands count as zero.
+        assert_eq!(fold(&U64Add, &[b"bad", &two]), Some(two.to_vec()));
+        assert_eq!(fold(&U64Add, &[&two, b""]), Some(two.to_vec()));
+    }
+
+    #[test]
+    fn max() {
+        assert_eq!(fold(&Max, &[b"b", b"a", b"c", b"bb"]), Some(b"c".to_vec()));
+        assert_eq!(fold(&Max, &[b"", b"\x00"]), Some(b"\x00".to_vec()));
+    }
+
+    #[test]
+    fn string_append() {
+        let op = StringAppend::new(", ");
+        assert_eq!(fold(&op, &[b"a", b"b", b"c"]), Some(b"a, b, c".to_vec()));
+        assert_eq!(
+            fold(&StringAppend::default(), &[b"a", b"b"]),
+            Some(b"ab".to_vec())
+        );
+        assert_eq!(op.merge(b"key", Some(b""), b"a"), b", a".to_vec());
+    }
+
+    #[test]
+    fn set_union() {
+        let op = SetUnion::new(b',');
+        assert_eq!(
+            fold(&op, &[b"c,a", b"b,a", b",,d,"]),
+            Some(b"a,b,c,d".to_vec())
+        );
+        assert_eq!(op.merge(b"key", None, b""), b"".to_vec());
+        assert_eq!(op.merge(b"key", Some(b"x"), b"x"), b"x".to_vec());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use std::ops::ControlFlow;
+
+/// Key under which a migration keeps its checkpoint in the target engine until it
+/// completes. It starts with `0xFF`, so no key passed as `&str` can collide with it.
+pub const CHECKPOINT_KEY: &[u8] = b"\xff\xfbpmemkv-migrate:checkpoint";
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+type RewriteFn<'a> = dyn Fn(&[u8]) -> Option<Vec<u8>> + 'a;
+
+/// Outcome of `Migration::run`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Pairs written to the target, including those of an interrupted earlier run.
+    pub copied: u64,
+    /// Pairs the rewrite hook dropped.
+    pub skipped: u64,
+    /// Batches written by this run.
+    pub batches: u64,
+    /// Number of source pairs an interrupted earlier run had already handled.
+    pub resumed_at: u64,
+}
+
+/// Outcome of `Migration::verify`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Verification {
+    pub source_count: i64,
+    /// Pairs the target should hold: the source count minus the pairs the rewrite
+    /// hook drops.
+    pub expected_count: i64,
+    pub target_count: i64,
+    pub source_digest: u64,
+    pub target_digest: u64,
+}
+
+impl Verification {
+    pub fn is_match(&self) -> bool {
+        self.expected_count == self.target_count && self.source_digest == self.target_digest
+    }
+}
+
+// FNV-1a over the key length, the key and the value. Digests add these up, so they
+// do not depend on scan order, which a rewrite hook or an unordered engine changes.
+fn pair_hash(key: &[u8], value: &[u8]) -> u64 {
+    let len = (key.len() as u32).to_le_bytes();
+    len.iter()
+        .chain(key)
+        .chain(value)
+        .fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+// Checkpoint layout: the number of source pairs visited, copied and skipped, all
+// big-endian u64, then the last source key visited.
+#[derive(Default)]
+struct Checkpoint {
+    position: u64,
+    copied: u64,
+    skipped: u64,
+    last_key: Vec<u8>,
+}
+
+impl Checkpoint {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(24 + self.last_key.len());
+        buf.extend_from_slice(&self.position.to_be_bytes());
+        buf.extend_from_slice(&self.copied.to_be_bytes());
+        buf.extend_from_slice(&self.skipped.to_be_bytes());
+        buf.extend_from_slice(&self.last_key);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Checkpoint> {
+        if buf.len() < 24 {
+            return None;
+        }
+        let field = |i: usize| {
+            let mut n = [0u8; 8];
+            n.copy_from_slice(&buf[i * 8..i * 8 + 8]);
+            u64::from_be_bytes(n)
+        };
+        Some(Checkpoint {
+            position: field(0),
+            copied: field(1),
+            skipped: field(2),
+            last_key: buf[24..].to_vec(),
+        })
+    }
+}
+
+/// Copies every pair of one engine into another, for instance to move a dataset
+/// from `vsmap` to `cmap` or to a bigger pool.
+///
+/// Pairs are written in batches, each followed by a checkpoint in the target, so a
+/// run interrupted by a crash resumes after the last complete batch. Puts are
+/// idempotent, so pairs of a batch cut short are simply written again.
+pub struct Migration<'a> {
+    source: &'a KVEngine,
+    target: &'a mut KVEngine,
+    batch_size: usize,
+    rewrite: Option<Box<RewriteFn<'a>>>,
+}
+
+impl<'a> Migration<'a> {
+    pub fn new(source: &'a KVEngine, target: &'a mut KVEngine) -> Migration<'a> {
+        Migration {
+            source,
+            target,
+            batch_size: DEFAULT_BATCH_SIZE,
+            rewrite: None,
+        }
+    }
+
+    /// Number of pairs written between two checkpoints.
+    pub fn batch_size(mut self, batch_size: usize) -> Migration<'a> {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Maps every source key to the key it is stored under in the target, or to
+    /// `None` to leave the pair out.
+    pub fn rewrite<F>(mut self, rewrite: F) -> Migration<'a>
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + 'a,
+    {
+        self.rewrite = Some(Box::new(rewrite));
+        self
+    }
+
+    fn target_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.rewrite {
+            Some(ref rewrite) => rewrite(key),
+            None => Some(key.to_vec()),
+        }
+    }
+
+    /// Whether the target holds the checkpoint of an unfinished run.
+    pub fn is_interrupted(&self) -> bool {
+        self.target.exists_raw(CHECKPOINT_KEY)
+    }
+
+    fn write_batch(
+        &mut self,
+        batch: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        checkpoint: &Checkpoint,
+    ) -> Result<()> {
+        for (k, v) in batch.drain(..) {
+            self.target.put_raw(&k, &v)?;
+        }
+        self.target.put_raw(CHECKPOINT_KEY, &checkpoint.encode())
+    }
+
+    /// Copies the source into the target, resuming an interrupted run if the target
+    /// holds its checkpoint, and removes the checkpoint once done.
+    pub fn run(&mut self) -> Result<MigrationReport> {
+        let mut report = MigrationReport::default();
+        // A checkpoint records how many pairs of the source scan were handled. The
+        // scan order of an unchanged source is stable, and the last key visited
+        // confirms it; otherwise the copy starts over.
+        let resume = self
+            .target
+            .get_copy_raw(CHECKPOINT_KEY)
+            .and_then(|buf| Checkpoint::decode(&buf));
+        let mut pending = resume.filter(|resume| resume.position > 0);
+        report.resumed_at = pending.as_ref().map_or(0, |resume| resume.position);
+        let mut checkpoint = Checkpoint::default();
+        let mut stale = false;
+
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let mut result = Ok(());
+        let mut position = 0u64;
+        let source = self.source;
+        source.each_raw(&mut |k, v| {
+            position += 1;
+            if let Some(ref resume) = pending {
+                if position < resume.position {
+                    return ControlFlow::Continue(());
+                }
+                if k != &resume.last_key[..] {
+                    stale = true;
+                    return ControlFlow::Break(());
+                }
+                checkpoint = pending.take().unwrap();
+                return ControlFlow::Continue(());
+            }
+            checkpoint.position = position;
+            checkpoint.last_key.clear();
+            checkpoint.last_key.extend_from_slice(k);
+            if k == CHECKPOINT_KEY {
+                return ControlFlow::Continue(());
+            }
+            match self.target_key(k) {
+                Some(key) => {
+                    batch.push((key, v.to_vec()));
+                    checkpoint.copied += 1;
+                }
+                None => checkpoint.skipped += 1,
+            }
+            if batch.len() < self.batch_size {
+                return ControlFlow::Continue(());
+            }
+            report.batches += 1;
+            match self.write_batch(&mut batch, &checkpoint) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(e) => {
+                    result = Err(e);
+                    ControlFlow::Break(())
+                }
+            }
+        });
+        result?;
+        if stale || pending.is_some() {
+            // The source changed since the checkpoint was written.
+            self.target.remove_raw(CHECKPOINT_KEY)?;
+            return self.run();
+        }
+        if !batch.is_empty() {
+            report.batches += 1;
+            self.write_batch(&mut batch, &checkpoint)?;
+        }
+        match self.target.remove_raw(CHECKPOINT_KEY) {
+            Err(Error(ErrorKind::NotFound(_), _)) | Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+        report.copied = checkpoint.copied;
+        report.skipped = checkpoint.skipped;
+        Ok(report)
+    }
+
+    /// Compares the pair counts of both engines and an order-independent digest of
+    /// their contents, with source keys passed through the rewrite hook. Pairs the
+    /// target held before the migration show up as a mismatch.
+    pub fn verify(&self) -> Verification {
+        let mut verification = Verification {
+            source_count: self.source.count(),
+            target_count: self.target.count(),
+            ..Verification::default()
+        };
+        let mut kept = 0i64;
+        self.source.each_raw(&mut |k, v| {
+            if k != CHECKPOINT_KEY {
+                if let Some(key) = self.target_key(k) {
+                    kept += 1;
+                    verification.source_digest =
+                        verification.source_digest.wrapping_add(pair_hash(&key, v));
+                }
+            }
+            ControlFlow::Continue(())
+        });
+        verification.expected_count = kept;
+        self.target.each_raw(&mut |k, v| {
+            if k == CHECKPOINT_KEY {
+                verification.target_count -= 1;
+            } else {
+                verification.target_digest =
+                    verification.target_digest.wrapping_add(pair_hash(k, v));
+            }
+            ControlFlow::Continue(())
+        });
+        verification
+    }
+}
+
+/// Copies every pair of `source` into `target` with the default settings.
+pub fn migrate(source: &KVEngine, target: &mut KVEngine) -> Result<MigrationReport> {
+    Migration::new(source, target).run()
+}
@@ -0,0 +1,299 @@
+use crate::errors::*;
+use crate::kvengine::{EngineRef, KVEngine, SharedEngine};
+use crate::range;
+use crate::sequence;
+use std::collections::BTreeMap;
+use std::ops::{Bound, ControlFlow};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const TAG_VALUE: u8 = 1;
+const TAG_TOMBSTONE: u8 = 0;
+// Version keys read from the engine per lock acquisition by full scans.
+const SCAN_WINDOW: usize = 256;
+
+// Physical layout: every write of `key` is stored under its own version key,
+// `escape(key) 00 01 !seq`. Escaping maps `00` to `00 FF`, which keeps user keys in
+// their natural order, and inverting the sequence number puts the newest version of a
+// key first. Values are prefixed with TAG_VALUE, or are a lone TAG_TOMBSTONE for removes.
+fn key_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(key.len() + 2);
+    for &b in key {
+        prefix.push(b);
+        if b == 0 {
+            prefix.push(0xFF);
+        }
+    }
+    prefix.extend_from_slice(&[0, 1]);
+    prefix
+}
+
+fn version_key(key: &[u8], seq: u64) -> Vec<u8> {
+    let mut vk = key_prefix(key);
+    vk.extend_from_slice(&(!seq).to_be_bytes());
+    vk
+}
+
+// Splits a version key into the escaped user key prefix and the sequence number.
+fn split_version_key(vk: &[u8]) -> Option<(&[u8], u64)> {
+    if vk.len() < 10 || vk[vk.len() - 10..vk.len() - 8] != [0, 1] {
+        return None;
+    }
+    let (prefix, seq) = vk.split_at(vk.len() - 8);
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(seq);
+    Some((prefix, !u64::from_be_bytes(buf)))
+}
+
+fn unescape(prefix: &[u8]) -> Vec<u8> {
+    let escaped = &prefix[..prefix.len() - 2];
+    let mut key = Vec::with_capacity(escaped.len());
+    let mut i = 0;
+    while i < escaped.len() {
+        key.push(escaped[i]);
+        i += if escaped[i] == 0 { 2 } else { 1 };
+    }
+    key
+}
+
+fn visible(value: &[u8]) -> Option<&[u8]> {
+    match value.split_first() {
+        Some((&TAG_VALUE, rest)) => Some(rest),
+        _ => None,
+    }
+}
+
+// The newest version of `key` with a sequence number not above `seq`.
+fn read_at(engine: &KVEngine, key: &[u8], seq: u64) -> Option<Vec<u8>> {
+    let mut found = None;
+    engine.each_prefix_raw(&key_prefix(key), &mut |vk, v| match split_version_key(vk) {
+        Some((_, version)) if version <= seq => {
+            found = visible(v).map(|v| v.to_vec());
+            ControlFlow::Break(())
+        }
+        _ => ControlFlow::Continue(()),
+    });
+    found
+}
+
+/// Engine wrapper keeping multiple versions of every key so that readers can work on
+/// a consistent `Snapshot` while writers go on. Requires an ordered engine, which
+/// should only be accessed through the wrapper.
+pub struct MvccEngine {
+    // Writers hold the engine lock while they draw a sequence number and write, so a
+    // snapshot taken under it never misses an earlier write.
+    engine: SharedEngine,
+    // Sequence numbers of the live snapshots, with their reference counts.
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+}
+
+/// Read view of an `MvccEngine` as of the moment `snapshot` was called.
+pub struct Snapshot<'a> {
+    db: &'a MvccEngine,
+    seq: u64,
+}
+
+/// Background garbage collector started by `MvccEngine::start_gc`. Dropping the
+/// handle stops the thread.
+pub struct GcHandle {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MvccEngine {
+    pub fn new(engine: KVEngine) -> MvccEngine {
+        MvccEngine {
+            engine: SharedEngine::new(engine),
+            snapshots: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Locks the engine for reads until the returned guard is dropped.
+    pub fn engine(&self) -> EngineRef<'_> {
+        self.engine.read()
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine.into_inner()
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(value.len() + 1);
+        buf.push(TAG_VALUE);
+        buf.extend_from_slice(value);
+        let mut engine = self.engine.lock();
+        engine.put_raw(&version_key(key.as_bytes(), sequence::next()), &buf)
+    }
+
+    /// Removes `key`. The check that it exists and the tombstone are written under one
+    /// lock, so of concurrent removes of a key only one succeeds.
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let mut engine = self.engine.lock();
+        if read_at(&engine, key.as_bytes(), u64::MAX).is_none() {
+            bail!(ErrorKind::NotFound(key.to_string()));
+        }
+        engine.put_raw(
+            &version_key(key.as_bytes(), sequence::next()),
+            &[TAG_TOMBSTONE],
+        )
+    }
+
+    /// Reads the latest value of `key`.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(read_at(&self.engine.lock(), key.as_bytes(), u64::MAX))
+    }
+
+    /// Pins the current state of the engine until the returned snapshot is dropped.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        let _engine = self.engine.lock();
+        let seq = sequence::next();
+        *self
+            .snapshots
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(seq)
+            .or_insert(0) += 1;
+        Snapshot { db: self, seq }
+    }
+
+    // Walks every version key in order, `SCAN_WINDOW` keys at a time. The engine is
+    // only locked while a window is read, so `f` runs unlocked and writers go on in
+    // between; versions written meanwhile may or may not be visited.
+    fn scan_versions<F>(&self, mut f: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let mut lower = Some(Bound::Included(Vec::new()));
+        while let Some(from) = lower {
+            let (entries, rest) = range::window_from(&self.engine.lock(), from, SCAN_WINDOW);
+            for (k, v) in entries {
+                f(&k, &v);
+            }
+            lower = rest;
+        }
+    }
+
+    /// Removes the versions no live snapshot can see any more. Returns how many
+    /// version keys were deleted.
+    pub fn collect_garbage(&self) -> Result<usize> {
+        // Versions written after this point are left alone, so a snapshot taken while
+        // the scan runs keeps what it can see.
+        let horizon = {
+            let _engine = self.engine.lock();
+            let snapshots = self.snapshots.lock().unwrap_or_else(|e| e.into_inner());
+            let now = sequence::next();
+            snapshots
+                .keys()
+                .next()
+                .map_or(now, |&oldest| oldest.min(now))
+        };
+        let mut garbage = Vec::new();
+        let mut current: Option<Vec<u8>> = None;
+        let mut covered = false;
+        self.scan_versions(|vk, v| {
+            let (prefix, seq) = match split_version_key(vk) {
+                Some(split) => split,
+                None => return,
+            };
+            if current.as_deref() != Some(prefix) {
+                current = Some(prefix.to_vec());
+                covered = false;
+            }
+            if seq > horizon {
+                return;
+            }
+            // The first version at or below the horizon is what the oldest snapshot
+            // sees; everything older is unreachable, and so is a tombstone there.
+            if covered || visible(v).is_none() {
+                garbage.push(vk.to_vec());
+            }
+            covered = true;
+        });
+        let mut engine = self.engine.lock();
+        for vk in &garbage {
+            match engine.remove_raw(vk) {
+                Err(Error(ErrorKind::NotFound(_), _)) | Ok(()) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(garbage.len())
+    }
+
+    /// Runs `collect_garbage` on a background thread every `interval`.
+    pub fn start_gc(db: &Arc<MvccEngine>, interval: Duration) -> GcHandle {
+        let (stop, stopped) = mpsc::channel();
+        let db = Arc::clone(db);
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = db.collect_garbage();
+                }
+                _ => return,
+            }
+        });
+        GcHandle {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(read_at(&self.db.engine.lock(), key.as_bytes(), self.seq))
+    }
+
+    /// Visits every live pair as of the snapshot, in key order. The engine is read a
+    /// window at a time and is not locked while `callback` runs.
+    pub fn each<F>(&self, mut callback: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let mut current: Option<Vec<u8>> = None;
+        let mut done = false;
+        self.db.scan_versions(|vk, v| {
+            let (prefix, seq) = match split_version_key(vk) {
+                Some(split) => split,
+                None => return,
+            };
+            if current.as_deref() != Some(prefix) {
+                current = Some(prefix.to_vec());
+                done = false;
+            }
+            if done || seq > self.seq {
+                return;
+            }
+            done = true;
+            if let Some(value) = visible(v) {
+                callback(&unescape(prefix), value);
+            }
+        });
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        let mut snapshots = self.db.snapshots.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = snapshots.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&self.seq);
+            }
+        }
+    }
+}
+
+impl Drop for GcHandle {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
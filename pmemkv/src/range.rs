@@ -0,0 +1,392 @@
+use crate::kvengine::KVEngine;
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::{Bound, ControlFlow, RangeBounds};
+
+const DEFAULT_WINDOW: usize = 64;
+const MAX_BISECT_STEPS: usize = 64;
+const MAX_PROBE_BYTES: usize = 16;
+
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Double-ended iterator over the pairs of an ordered engine whose keys fall in a
+/// range. Entries are pulled from the engine in windows of roughly `window` pairs,
+/// so walking backwards from the upper bound does not visit the whole range.
+pub struct Range<'a> {
+    engine: &'a KVEngine,
+    // Remaining, not yet fetched part of the range. The lower bound is never
+    // `Unbounded`: the empty key is the smallest key.
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    front: VecDeque<Entry>,
+    back: VecDeque<Entry>,
+    window: usize,
+    done: bool,
+}
+
+fn to_owned_bound(bound: Bound<&&str>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(k) => Bound::Included(k.as_bytes().to_vec()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_bytes().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn bound_key(bound: &Bound<Vec<u8>>) -> Option<&[u8]> {
+    match bound {
+        Bound::Included(k) | Bound::Excluded(k) => Some(k),
+        Bound::Unbounded => None,
+    }
+}
+
+// A key strictly between `a` and `b` (a < b) in lexicographic order, computed by
+// reading both as base-256 fractions with one extra digit of precision. Keys that only
+// differ in trailing zero bytes read as the same fraction; the successor of `a` is
+// taken then, and `None` returned if no key lies between.
+fn midpoint(a: &[u8], b: &[u8]) -> Option<Vec<u8>> {
+    let len = a.len().max(b.len()) + 1;
+    let digit = |s: &[u8], i: usize| u32::from(s.get(i).cloned().unwrap_or(0));
+    let mut sum = vec![0u32; len];
+    let mut carry = 0;
+    for i in (0..len).rev() {
+        let d = digit(a, i) + digit(b, i) + carry;
+        sum[i] = d & 0xFF;
+        carry = d >> 8;
+    }
+    let mut mid = Vec::with_capacity(len);
+    let mut rem = carry;
+    for d in sum {
+        let cur = (rem << 8) | d;
+        mid.push((cur / 2) as u8);
+        rem = cur % 2;
+    }
+    if a < &mid[..] && &mid[..] < b {
+        return Some(mid);
+    }
+    let mut next = a.to_vec();
+    next.push(0);
+    if &next[..] < b {
+        Some(next)
+    } else {
+        None
+    }
+}
+
+impl<'a> Range<'a> {
+    fn new(engine: &'a KVEngine, lower: Bound<Vec<u8>>, upper: Bound<Vec<u8>>) -> Range<'a> {
+        let lower = match lower {
+            Bound::Unbounded => Bound::Included(Vec::new()),
+            b => b,
+        };
+        Range {
+            engine,
+            lower,
+            upper,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            window: DEFAULT_WINDOW,
+            done: false,
+        }
+    }
+
+    /// Sets how many pairs are fetched from the engine at a time.
+    pub fn window(mut self, window: usize) -> Range<'a> {
+        self.window = window.max(1);
+        self
+    }
+
+    fn is_empty(lower: &Bound<Vec<u8>>, upper: &Bound<Vec<u8>>) -> bool {
+        match (lower, upper) {
+            (Bound::Included(l), Bound::Included(u)) => l > u,
+            (Bound::Included(l), Bound::Excluded(u))
+            | (Bound::Excluded(l), Bound::Included(u))
+            | (Bound::Excluded(l), Bound::Excluded(u)) => l >= u,
+            _ => false,
+        }
+    }
+
+    fn count_in(&self, lower: &Bound<Vec<u8>>, upper: &Bound<Vec<u8>>) -> i64 {
+        if Range::is_empty(lower, upper) {
+            return 0;
+        }
+        let lo = bound_key(lower).unwrap_or_default();
+        let mut n = match upper {
+            Bound::Included(u) | Bound::Excluded(u) => self.engine.count_between_raw(lo, u),
+            Bound::Unbounded => self.engine.count_above_raw(lo),
+        };
+        if let Bound::Included(l) = lower {
+            n += self.engine.exists_raw(l) as i64;
+        }
+        if let Bound::Included(u) = upper {
+            if bound_key(lower) != Some(u.as_slice()) {
+                n += self.engine.exists_raw(u) as i64;
+            }
+        }
+        n
+    }
+
+    fn fetch(&self, lower: &Bound<Vec<u8>>, upper: &Bound<Vec<u8>>) -> VecDeque<Entry> {
+        let mut entries = VecDeque::new();
+        if Range::is_empty(lower, upper) {
+            return entries;
+        }
+        let lo = bound_key(lower).unwrap_or_default();
+        if let Bound::Included(l) = lower {
+            self.engine
+                .get_raw(l, &mut |v| entries.push_back((l.clone(), v.to_vec())));
+        }
+        let mut push = |k: &[u8], v: &[u8]| {
+            entries.push_back((k.to_vec(), v.to_vec()));
+            ControlFlow::Continue(())
+        };
+        match upper {
+            Bound::Included(u) | Bound::Excluded(u) => {
+                self.engine.each_between_raw(lo, u, &mut push)
+            }
+            Bound::Unbounded => self.engine.each_above_raw(lo, &mut push),
+        }
+        if let Bound::Included(u) = upper {
+            if lo != u.as_slice() {
+                self.engine
+                    .get_raw(u, &mut |v| entries.push_back((u.clone(), v.to_vec())));
+            }
+        }
+        entries
+    }
+
+    // Replaces an unbounded upper end with an exclusive bound above every stored key,
+    // so the range can be bisected. Left unbounded if no short probe key qualifies.
+    fn close_upper(&mut self) {
+        if let Bound::Unbounded = self.upper {
+            let mut probe = vec![0xFF];
+            while probe.len() <= MAX_PROBE_BYTES {
+                if self.engine.count_above_raw(&probe) == 0 && !self.engine.exists_raw(&probe) {
+                    self.upper = Bound::Excluded(probe);
+                    return;
+                }
+                probe.push(0xFF);
+            }
+        }
+    }
+
+    // Fetches all of the remaining range at once.
+    fn fetch_rest(&mut self, from_back: bool) {
+        let entries = self.fetch(&self.lower, &self.upper);
+        if from_back {
+            self.back = entries;
+        } else {
+            self.front = entries;
+        }
+        self.done = true;
+    }
+
+    // Pulls the next window from the remaining range. `from_back` picks whether the
+    // window is taken at the upper or the lower end.
+    fn refill(&mut self, from_back: bool) {
+        if self.done {
+            return;
+        }
+        self.close_upper();
+        let total = self.count_in(&self.lower, &self.upper);
+        let window = self.window as i64;
+        if total <= window || bound_key(&self.upper).is_none() {
+            return self.fetch_rest(from_back);
+        }
+        let mut lo = bound_key(&self.lower).unwrap_or_default().to_vec();
+        let mut hi = bound_key(&self.upper).unwrap_or_default().to_vec();
+        let mut split = match midpoint(&lo, &hi) {
+            Some(split) => split,
+            None => return self.fetch_rest(from_back),
+        };
+        for _ in 0..MAX_BISECT_STEPS {
+            let n = if from_back {
+                self.count_in(&Bound::Excluded(split.clone()), &self.upper)
+            } else {
+                self.count_in(&self.lower, &Bound::Excluded(split.clone()))
+            };
+            if n > 0 && n <= window {
+                break;
+            }
+            if (n > window) == from_back {
+                lo = split.clone();
+            } else {
+                hi = split.clone();
+            }
+            match midpoint(&lo, &hi) {
+                Some(mid) => split = mid,
+                None => break,
+            }
+        }
+        if from_back {
+            self.back = self.fetch(&Bound::Excluded(split.clone()), &self.upper);
+            self.upper = Bound::Included(split);
+        } else {
+            self.front = self.fetch(&self.lower, &Bound::Excluded(split.clone()));
+            self.lower = Bound::Included(split);
+        }
+    }
+}
+
+/// Fetches about `window` pairs from the start of the range `lower..`, and returns them
+/// with the lower bound of the rest of the range, or `None` once nothing is left. Lets
+/// callers walk an engine a window at a time without holding on to it in between.
+pub(crate) fn window_from(
+    engine: &KVEngine,
+    lower: Bound<Vec<u8>>,
+    window: usize,
+) -> (VecDeque<Entry>, Option<Bound<Vec<u8>>>) {
+    let mut range = Range::new(engine, lower, Bound::Unbounded).window(window);
+    range.refill(false);
+    let rest = if range.done { None } else { Some(range.lower) };
+    (mem::take(&mut range.front), rest)
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        while self.front.is_empty() && !self.done {
+            self.refill(false);
+        }
+        self.front.pop_front().or_else(|| self.back.pop_front())
+    }
+}
+
+impl<'a> DoubleEndedIterator for Range<'a> {
+    fn next_back(&mut self) -> Option<Entry> {
+        while self.back.is_empty() && !self.done {
+            self.refill(true);
+        }
+        self.back.pop_back().or_else(|| self.front.pop_back())
+    }
+}
+
+impl KVEngine {
+    /// Iterates the pairs whose keys fall in `range`, in either direction. Requires an
+    /// ordered engine.
+    pub fn range<'k, R>(&self, range: R) -> Range<'_>
+    where
+        R: RangeBounds<&'k str>,
+    {
+        Range::new(
+            self,
+            to_owned_bound(range.start_bound()),
+            to_owned_bound(range.end_bound()),
+        )
+    }
+
+    /// The pair with the smallest key.
+    pub fn first(&self) -> Option<Entry> {
+        self.range(..).window(1).next()
+    }
+
+    /// The pair with the largest key.
+    pub fn last(&self) -> Option<Entry> {
+        self.range(..).window(1).next_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(n: usize) -> KVEngine {
+        let config = r#"{"path":"/dev/shm","size":1073741824}"#;
+        let mut engine =
+            KVEngine::start_string("vsmap", config, None::<fn(&str, &str, &str)>).unwrap();
+        for i in 0..n {
+            engine.put(&format!("k{:03}", i), &i.to_string()).unwrap();
+        }
+        engine
+    }
+
+    fn keys<I: Iterator<Item = Entry>>(entries: I) -> Vec<String> {
+        entries
+            .map(|(k, _)| String::from_utf8(k).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn midpoint_between() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"a", b"c"),
+            (b"a", b"b"),
+            (b"", b"\x01"),
+            (b"abc", b"abd"),
+            (b"k010", b"k999"),
+            (b"\xFE", b"\xFF"),
+        ];
+        for &(lo, hi) in cases {
+            let mid = midpoint(lo, hi).unwrap();
+            assert!(
+                lo < &mid[..] && &mid[..] < hi,
+                "{:?} {:?} {:?}",
+                lo,
+                mid,
+                hi
+            );
+        }
+        assert_eq!(midpoint(b"a", b"c"), Some(b"b\x00".to_vec()));
+        // Equal as fractions: the successor of the lower key is inside.
+        assert_eq!(midpoint(b"a", b"a\x00\x00"), Some(b"a\x00".to_vec()));
+        assert_eq!(midpoint(b"", b"\x00\x00"), Some(b"\x00".to_vec()));
+        assert_eq!(midpoint(b"a", b"a\x00"), None);
+        assert_eq!(midpoint(b"", b"\x00"), None);
+    }
+
+    #[test]
+    fn first_and_last() {
+        let engine = filled(50);
+        assert_eq!(engine.first(), Some((b"k000".to_vec(), b"0".to_vec())));
+        assert_eq!(engine.last(), Some((b"k049".to_vec(), b"49".to_vec())));
+        let empty = filled(0);
+        assert_eq!(empty.first(), None);
+        assert_eq!(empty.last(), None);
+    }
+
+    #[test]
+    fn both_directions() {
+        let engine = filled(100);
+        let all: Vec<String> = (0..100).map(|i| format!("k{:03}", i)).collect();
+        assert_eq!(keys(engine.range(..).window(7)), all);
+        let mut reversed = all.clone();
+        reversed.reverse();
+        assert_eq!(keys(engine.range(..).window(7).rev()), reversed);
+        assert_eq!(keys(engine.range("k010".."k020").window(3)), all[10..20]);
+        assert_eq!(
+            keys(engine.range("k010"..="k020").window(3).rev()).len(),
+            11
+        );
+        assert_eq!(keys(engine.range("k095"..)), all[95..]);
+        // Both ends meet in the middle without losing or repeating pairs.
+        let mut range = engine.range("k020"..="k079").window(5);
+        let mut seen = Vec::new();
+        loop {
+            match (range.next(), range.next_back()) {
+                (None, None) => break,
+                (front, back) => seen.extend(front.into_iter().chain(back)),
+            }
+        }
+        let mut seen = keys(seen.into_iter());
+        seen.sort();
+        assert_eq!(seen, all[20..80]);
+    }
+
+    #[test]
+    fn trailing_zero_keys() {
+        let mut engine = filled(0);
+        let all = ["a", "a\0", "a\0\0", "a\0\0\0", "b"];
+        for key in &all {
+            engine.put(key, "v").unwrap();
+        }
+        assert_eq!(keys(engine.range("a".."a\0\0").window(1)), all[..2]);
+        assert_eq!(
+            keys(engine.range("a".."a\0\0").window(1).rev()),
+            ["a\0", "a"]
+        );
+        assert_eq!(keys(engine.range(..).window(1)), all);
+        let mut reversed = all.to_vec();
+        reversed.reverse();
+        assert_eq!(keys(engine.range(..).window(1).rev()), reversed);
+    }
+}
@@ -0,0 +1,432 @@
+use crate::errors::*;
+use crate::kvengine::{KVEngine, RawAllFn, RawEachFn};
+use crate::range::Entry;
+use std::ops::ControlFlow;
+use std::os::raw::c_char;
+use std::slice;
+
+/// Returns the smallest key that is greater than every key starting with `prefix`,
+/// or `None` when no such key exists (the prefix is empty or made only of `0xFF`).
+pub fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last != 0xFF {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
+/// Matches `key` against a Redis-style glob `pattern`: `*`, `?`, `[abc]`, `[^a-z]`
+/// and `\` to escape the next byte.
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() {
+            let step = match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, k));
+                    p += 1;
+                    continue;
+                }
+                b'?' => Some(p + 1),
+                b'[' => match match_class(pattern, p, key[k]) {
+                    Some((true, next)) => Some(next),
+                    Some((false, _)) => None,
+                    None if key[k] == b'[' => Some(p + 1),
+                    None => None,
+                },
+                b'\\' if p + 1 < pattern.len() && pattern[p + 1] == key[k] => Some(p + 2),
+                b'\\' if p + 1 < pattern.len() => None,
+                c if c == key[k] => Some(p + 1),
+                _ => None,
+            };
+            if let Some(next) = step {
+                p = next;
+                k += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            Some((star, consumed)) => {
+                p = star + 1;
+                k = consumed + 1;
+                backtrack = Some((star, k));
+            }
+            None => return false,
+        }
+    }
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+// Evaluates the `[...]` class starting at `start` against `c`. Returns whether it
+// matched and the index just past the closing `]`, or `None` if the class is unterminated.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = i < pattern.len() && pattern[i] == b'^';
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    if i < pattern.len() {
+        Some((matched != negate, i + 1))
+    } else {
+        None
+    }
+}
+
+// The bytes every key matching `pattern` must start with.
+fn literal_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'*' | b'?' | b'[' => break,
+            b'\\' if i + 1 < pattern.len() => {
+                prefix.push(pattern[i + 1]);
+                i += 2;
+            }
+            c => {
+                prefix.push(c);
+                i += 1;
+            }
+        }
+    }
+    prefix
+}
+
+fn as_c_chars(bytes: &[u8]) -> &[c_char] {
+    unsafe { slice::from_raw_parts(bytes.as_ptr() as *const c_char, bytes.len()) }
+}
+
+impl KVEngine {
+    // each_above/each_between exclude their bounds, so the key equal to the prefix
+    // is fetched on its own before the range; it sorts first among the matches.
+    pub(crate) fn each_prefix_raw(&self, prefix: &[u8], callback: &mut RawEachFn) {
+        if prefix.is_empty() {
+            self.each_raw(callback);
+            return;
+        }
+        let mut flow = ControlFlow::Continue(());
+        self.get_raw(prefix, &mut |v| flow = callback(prefix, v));
+        if flow.is_break() {
+            return;
+        }
+        match prefix_upper_bound(prefix) {
+            Some(upper) => self.each_between_raw(prefix, &upper, callback),
+            None => self.each_above_raw(prefix, callback),
+        }
+    }
+
+    /// Visits every pair whose key starts with `prefix`, in key order. Requires an
+    /// ordered engine unless `prefix` is empty.
+    pub fn scan_prefix<F>(&self, prefix: &str, callback: Option<F>) -> Result<()>
+    where
+        F: Fn(&[c_char], &[c_char]),
+    {
+        if let Some(f) = callback {
+            self.each_prefix_raw(prefix.as_bytes(), &mut |k, v| {
+                f(as_c_chars(k), as_c_chars(v));
+                ControlFlow::Continue(())
+            });
+        }
+        Ok(())
+    }
+
+    pub fn scan_prefix_string<F>(&self, prefix: &str, callback: Option<F>) -> Result<()>
+    where
+        F: Fn(&str, &str),
+    {
+        if let Some(f) = callback {
+            self.each_prefix_raw(prefix.as_bytes(), &mut |k, v| {
+                f(
+                    std::str::from_utf8(k).unwrap_or_default(),
+                    std::str::from_utf8(v).unwrap_or_default(),
+                );
+                ControlFlow::Continue(())
+            });
+        }
+        Ok(())
+    }
+
+    fn each_match_raw(&self, pattern: &[u8], callback: &mut RawEachFn) {
+        let prefix = literal_prefix(pattern);
+        self.each_prefix_raw(&prefix, &mut |k, v| {
+            if glob_match(pattern, k) {
+                callback(k, v)
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+    }
+
+    /// Visits every pair whose key matches the glob `pattern` (see [`glob_match`]).
+    /// The literal prefix of the pattern narrows the scan, so patterns that start with
+    /// a wildcard walk the whole engine.
+    pub fn scan_match<F>(&self, pattern: &str, callback: Option<F>) -> Result<()>
+    where
+        F: Fn(&[c_char], &[c_char]),
+    {
+        if let Some(f) = callback {
+            self.each_match_raw(pattern.as_bytes(), &mut |k, v| {
+                f(as_c_chars(k), as_c_chars(v));
+                ControlFlow::Continue(())
+            });
+        }
+        Ok(())
+    }
+
+    pub fn scan_match_string<F>(&self, pattern: &str, callback: Option<F>) -> Result<()>
+    where
+        F: Fn(&str, &str),
+    {
+        if let Some(f) = callback {
+            self.each_match_raw(pattern.as_bytes(), &mut |k, v| {
+                f(
+                    std::str::from_utf8(k).unwrap_or_default(),
+                    std::str::from_utf8(v).unwrap_or_default(),
+                );
+                ControlFlow::Continue(())
+            });
+        }
+        Ok(())
+    }
+}
+
+// Adapts a user callback that may break with a value to the raw scan callbacks, and
+// reports how the scan ended.
+fn each_until<B, F, S>(mut callback: F, scan: S) -> ControlFlow<B>
+where
+    F: FnMut(&[u8], &[u8]) -> ControlFlow<B>,
+    S: FnOnce(&mut RawEachFn),
+{
+    let mut result = None;
+    scan(&mut |k, v| match callback(k, v) {
+        ControlFlow::Continue(()) => ControlFlow::Continue(()),
+        ControlFlow::Break(b) => {
+            result = Some(b);
+            ControlFlow::Break(())
+        }
+    });
+    match result {
+        Some(b) => ControlFlow::Break(b),
+        None => ControlFlow::Continue(()),
+    }
+}
+
+fn all_until<B, F, S>(mut callback: F, scan: S) -> ControlFlow<B>
+where
+    F: FnMut(&[u8]) -> ControlFlow<B>,
+    S: FnOnce(&mut RawAllFn),
+{
+    let mut result = None;
+    scan(&mut |k| match callback(k) {
+        ControlFlow::Continue(()) => ControlFlow::Continue(()),
+        ControlFlow::Break(b) => {
+            result = Some(b);
+            ControlFlow::Break(())
+        }
+    });
+    match result {
+        Some(b) => ControlFlow::Break(b),
+        None => ControlFlow::Continue(()),
+    }
+}
+
+// Early-terminating scans. Returning `ControlFlow::Break` from the callback ends the
+// scan: no further pairs are passed to it, and the rest of the engine's walk only
+// skips over entries.
+impl KVEngine {
+    pub fn each_until<B, F>(&self, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8], &[u8]) -> ControlFlow<B>,
+    {
+        each_until(callback, |cb| self.each_raw(cb))
+    }
+
+    pub fn each_above_until<B, F>(&self, key: &str, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8], &[u8]) -> ControlFlow<B>,
+    {
+        each_until(callback, |cb| self.each_above_raw(key.as_bytes(), cb))
+    }
+
+    pub fn each_below_until<B, F>(&self, key: &str, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8], &[u8]) -> ControlFlow<B>,
+    {
+        each_until(callback, |cb| self.each_below_raw(key.as_bytes(), cb))
+    }
+
+    pub fn each_between_until<B, F>(&self, key1: &str, key2: &str, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8], &[u8]) -> ControlFlow<B>,
+    {
+        each_until(callback, |cb| {
+            self.each_between_raw(key1.as_bytes(), key2.as_bytes(), cb)
+        })
+    }
+
+    pub fn each_prefix_until<B, F>(&self, prefix: &str, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8], &[u8]) -> ControlFlow<B>,
+    {
+        each_until(callback, |cb| self.each_prefix_raw(prefix.as_bytes(), cb))
+    }
+
+    pub fn all_until<B, F>(&self, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8]) -> ControlFlow<B>,
+    {
+        all_until(callback, |cb| self.all_raw(cb))
+    }
+
+    pub fn all_above_until<B, F>(&self, key: &str, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8]) -> ControlFlow<B>,
+    {
+        all_until(callback, |cb| self.all_above_raw(key.as_bytes(), cb))
+    }
+
+    pub fn all_below_until<B, F>(&self, key: &str, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8]) -> ControlFlow<B>,
+    {
+        all_until(callback, |cb| self.all_below_raw(key.as_bytes(), cb))
+    }
+
+    pub fn all_between_until<B, F>(&self, key1: &str, key2: &str, callback: F) -> ControlFlow<B>
+    where
+        F: FnMut(&[u8]) -> ControlFlow<B>,
+    {
+        all_until(callback, |cb| {
+            self.all_between_raw(key1.as_bytes(), key2.as_bytes(), cb)
+        })
+    }
+
+    /// The first pair, in engine order, for which `predicate` returns true.
+    pub fn find<P>(&self, mut predicate: P) -> Option<Entry>
+    where
+        P: FnMut(&[u8], &[u8]) -> bool,
+    {
+        match self.each_until(|k, v| {
+            if predicate(k, v) {
+                ControlFlow::Break((k.to_vec(), v.to_vec()))
+            } else {
+                ControlFlow::Continue(())
+            }
+        }) {
+            ControlFlow::Break(entry) => Some(entry),
+            ControlFlow::Continue(()) => None,
+        }
+    }
+
+    /// Whether any pair satisfies `predicate`.
+    pub fn any<P>(&self, mut predicate: P) -> bool
+    where
+        P: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.each_until(|k, v| {
+            if predicate(k, v) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .is_break()
+    }
+
+    /// The first `n` pairs in engine order.
+    pub fn take(&self, n: usize) -> Vec<Entry> {
+        let mut entries = Vec::with_capacity(n);
+        if n > 0 {
+            self.each_raw(&mut |k, v| {
+                entries.push((k.to_vec(), v.to_vec()));
+                if entries.len() < n {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            });
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upper_bound() {
+        assert_eq!(prefix_upper_bound(b"abc"), Some(b"abd".to_vec()));
+        assert_eq!(prefix_upper_bound(b"a\xFF\xFF"), Some(b"b".to_vec()));
+        assert_eq!(prefix_upper_bound(b"\xFF\xFF"), None);
+        assert_eq!(prefix_upper_bound(b""), None);
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_match(b"a*b", b"aXbY"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"k[0-9]", b"k7"));
+        assert!(glob_match(b"k[9-0]", b"k7"));
+        assert!(!glob_match(b"k[^0-9]", b"k7"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"aXb"));
+        assert!(glob_match(b"a\\", b"a\\"));
+        // An unterminated class matches a literal `[`.
+        assert!(glob_match(b"a[b", b"a[b"));
+        assert!(!glob_match(b"a[b", b"ab"));
+    }
+
+    #[test]
+    fn class() {
+        assert_eq!(match_class(b"[abc]", 0, b'b'), Some((true, 5)));
+        assert_eq!(match_class(b"[abc]", 0, b'd'), Some((false, 5)));
+        assert_eq!(match_class(b"[^abc]", 0, b'd'), Some((true, 6)));
+        assert_eq!(match_class(b"[\\]]", 0, b']'), Some((true, 4)));
+        assert_eq!(match_class(b"[\\^]", 0, b'^'), Some((true, 4)));
+        assert_eq!(match_class(b"[a-]", 0, b'-'), Some((true, 4)));
+        assert_eq!(match_class(b"[a-c", 0, b'b'), None);
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(literal_prefix(b"user:*"), b"user:");
+        assert_eq!(literal_prefix(b"a?b"), b"a");
+        assert_eq!(literal_prefix(b"[ab]c"), b"");
+        assert_eq!(literal_prefix(b"a\\*b*"), b"a*b");
+        assert_eq!(literal_prefix(b"ab\\"), b"ab\\");
+    }
+}
@@ -0,0 +1,22 @@
+use std::cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// Process-wide, strictly increasing sequence number. It starts from the clock so
+// numbers handed out after a restart are larger than the ones persisted before it.
+pub(crate) fn next() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let mut prev = SEQUENCE.load(Ordering::SeqCst);
+    loop {
+        let next = cmp::max(prev + 1, now);
+        match SEQUENCE.compare_exchange(prev, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return next,
+            Err(actual) => prev = actual,
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use crate::errors::*;
+use crate::kvengine::{EngineRef, KVEngine, SharedEngine};
+use crate::range::Entry;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::mem;
+use std::ops::ControlFlow;
+use std::panic;
+use std::sync::mpsc;
+use std::thread;
+
+// Hash-ordered engines, whose walks have to be sorted before they can be merged.
+const UNORDERED_ENGINES: &[&str] = &["cmap", "vcmap"];
+// Pairs a shard walk hands to the merge at a time. Each walk runs at most one chunk
+// ahead of the merge.
+const SCAN_CHUNK: usize = 256;
+
+// FNV-1a. Routing has to stay the same across runs and toolchains for persistent
+// shards, which rules out `DefaultHasher`.
+fn shard_hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Visits the entries of several runs, each sorted by key, in global key order. Equal
+/// keys are visited in the order of their runs; the callback receives the run index.
+fn merge_runs<F>(runs: &[Vec<Entry>], mut f: F)
+where
+    F: FnMut(usize, &[u8], &[u8]) -> ControlFlow<()>,
+{
+    let mut positions = vec![0; runs.len()];
+    let mut heap: BinaryHeap<Reverse<(&[u8], usize)>> = runs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, run)| run.first().map(|(k, _)| Reverse((k.as_slice(), i))))
+        .collect();
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let (k, v) = &runs[i][positions[i]];
+        if f(i, k, v).is_break() {
+            return;
+        }
+        positions[i] += 1;
+        if let Some((next, _)) = runs[i].get(positions[i]) {
+            heap.push(Reverse((next.as_slice(), i)));
+        }
+    }
+}
+
+/// Spreads keys over several `KVEngine`s by a stable hash of the key. Point operations
+/// only lock the shard owning the key; `count` and scans visit the shards in parallel,
+/// and scans merge the shard walks back into key order as they go.
+pub struct ShardedEngine {
+    shards: Vec<SharedEngine>,
+    unordered: bool,
+}
+
+impl ShardedEngine {
+    /// Builds a sharded engine over already started engines. Keys are routed by their
+    /// position, so reopen persistent shards in the same order.
+    pub fn new(engines: Vec<KVEngine>) -> Result<ShardedEngine> {
+        if engines.is_empty() {
+            bail!(ErrorKind::Fail);
+        }
+        let shards = engines.into_iter().map(SharedEngine::new).collect();
+        Ok(ShardedEngine {
+            shards,
+            unordered: false,
+        })
+    }
+
+    /// Declares the shards hash-ordered, like `cmap`. Scans then load and sort each
+    /// shard before merging instead of streaming it.
+    pub fn unordered(mut self) -> ShardedEngine {
+        self.unordered = true;
+        self
+    }
+
+    /// Starts one `engine` per pool path. Every shard gets the JSON object `config`,
+    /// such as `{"size":1073741824}`, with its path added; `config` should not set a
+    /// path itself.
+    pub fn start(engine: &str, config: &str, paths: &[&str]) -> Result<ShardedEngine> {
+        let members = match config
+            .trim()
+            .strip_prefix('{')
+            .and_then(|c| c.strip_suffix('}'))
+        {
+            Some(members) => members.trim(),
+            None => bail!(ErrorKind::InvalidFormat(config.to_string())),
+        };
+        let mut engines = Vec::with_capacity(paths.len());
+        for path in paths {
+            let mut shard_config = format!("{{\"path\":{}", json_string(path));
+            if !members.is_empty() {
+                shard_config.push(',');
+                shard_config.push_str(members);
+            }
+            shard_config.push('}');
+            engines.push(KVEngine::start_string(
+                engine,
+                &shard_config,
+                None::<fn(&str, &str, &str)>,
+            )?);
+        }
+        let sharded = ShardedEngine::new(engines)?;
+        if UNORDERED_ENGINES.contains(&engine) {
+            return Ok(sharded.unordered());
+        }
+        Ok(sharded)
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard owning `key`.
+    pub fn shard_for(&self, key: &str) -> usize {
+        self.shard_index(key.as_bytes())
+    }
+
+    /// Locks shard `index` for reads until the returned guard is dropped.
+    pub fn shard(&self, index: usize) -> Option<EngineRef<'_>> {
+        self.shards.get(index).map(SharedEngine::read)
+    }
+
+    pub fn into_inner(self) -> Vec<KVEngine> {
+        self.shards
+            .into_iter()
+            .map(SharedEngine::into_inner)
+            .collect()
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        (shard_hash(key) % self.shards.len() as u64) as usize
+    }
+
+    fn route(&self, key: &[u8]) -> &SharedEngine {
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.route(key.as_bytes())
+            .lock()
+            .put_raw(key.as_bytes(), value)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .route(key.as_bytes())
+            .lock()
+            .get_copy_raw(key.as_bytes()))
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.route(key.as_bytes()).lock().exists_raw(key.as_bytes())
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.route(key.as_bytes()).lock().remove_raw(key.as_bytes())
+    }
+
+    // Runs `f` on every shard, each on its own thread, and returns the results in
+    // shard order.
+    fn par_map<T, F>(&self, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(&KVEngine) -> T + Sync,
+    {
+        let f = &f;
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .shards
+                .iter()
+                .map(|shard| scope.spawn(move || f(&shard.lock())))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        })
+    }
+
+    pub fn count(&self) -> i64 {
+        self.par_map(|e| e.count()).into_iter().sum()
+    }
+
+    pub fn count_above(&self, key: &str) -> i64 {
+        let key = key.as_bytes();
+        self.par_map(|e| e.count_above_raw(key)).into_iter().sum()
+    }
+
+    pub fn count_below(&self, key: &str) -> i64 {
+        let key = key.as_bytes();
+        self.par_map(|e| e.count_below_raw(key)).into_iter().sum()
+    }
+
+    pub fn count_between(&self, key1: &str, key2: &str) -> i64 {
+        let (key1, key2) = (key1.as_bytes(), key2.as_bytes());
+        self.par_map(|e| e.count_between_raw(key1, key2))
+            .into_iter()
+            .sum()
+    }
+
+    // Walks every shard on its own thread and merges the walks into key order. A walk
+    // hands its pairs over in chunks through a channel with room for one, so it holds
+    // at most two chunks in memory, except over unordered shards, which are loaded and
+    // sorted whole.
+    fn scan<S, F>(&self, scan: S, mut callback: F)
+    where
+        S: Fn(&KVEngine, &mut dyn FnMut(&[u8], &[u8]) -> ControlFlow<()>) + Sync,
+        F: FnMut(&[u8], &[u8]),
+    {
+        let scan = &scan;
+        let unordered = self.unordered;
+        thread::scope(|scope| {
+            let mut walks: Vec<_> = self
+                .shards
+                .iter()
+                .map(|shard| {
+                    let (tx, rx) = mpsc::sync_channel::<Vec<Entry>>(1);
+                    scope.spawn(move || {
+                        let mut chunk = Vec::new();
+                        let mut open = true;
+                        scan(&shard.lock(), &mut |k, v| {
+                            chunk.push((k.to_vec(), v.to_vec()));
+                            if !unordered && chunk.len() == SCAN_CHUNK {
+                                // Fails once the merge is gone, e.g. after a panic.
+                                open = tx.send(mem::take(&mut chunk)).is_ok();
+                            }
+                            if open {
+                                ControlFlow::Continue(())
+                            } else {
+                                ControlFlow::Break(())
+                            }
+                        });
+                        if unordered {
+                            chunk.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                        }
+                        if open && !chunk.is_empty() {
+                            let _ = tx.send(chunk);
+                        }
+                    });
+                    rx.into_iter().flatten()
+                })
+                .collect();
+            let mut heap = BinaryHeap::new();
+            for (i, walk) in walks.iter_mut().enumerate() {
+                if let Some((k, v)) = walk.next() {
+                    heap.push(Reverse((k, i, v)));
+                }
+            }
+            while let Some(Reverse((k, i, v))) = heap.pop() {
+                callback(&k, &v);
+                if let Some((k, v)) = walks[i].next() {
+                    heap.push(Reverse((k, i, v)));
+                }
+            }
+        })
+    }
+
+    pub fn each<F>(&self, callback: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        self.scan(|e, f| e.each_raw(f), callback)
+    }
+
+    pub fn each_above<F>(&self, key: &str, callback: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let key = key.as_bytes();
+        self.scan(|e, f| e.each_above_raw(key, f), callback)
+    }
+
+    /// Up to `limit` pairs above `key`, in key order. Each shard stops after `limit`
+    /// pairs, so only those are merged. Fails with `ErrorKind::Unordered` over
+    /// unordered shards.
+    pub fn take_above(&self, key: &str, limit: usize) -> Result<Vec<Entry>> {
+        if self.unordered {
+            bail!(ErrorKind::Unordered);
+        }
+        let key = key.as_bytes();
+        let runs = self.par_map(|e| {
+            let mut run: Vec<Entry> = Vec::new();
+            if limit > 0 {
+                e.each_above_raw(key, &mut |k, v| {
+                    run.push((k.to_vec(), v.to_vec()));
+                    if run.len() < limit {
+                        ControlFlow::Continue(())
+                    } else {
+                        ControlFlow::Break(())
+                    }
+                });
+            }
+            run
+        });
+        let mut entries = Vec::with_capacity(limit);
+        merge_runs(&runs, |_, k, v| {
+            if entries.len() == limit {
+                return ControlFlow::Break(());
+            }
+            entries.push((k.to_vec(), v.to_vec()));
+            ControlFlow::Continue(())
+        });
+        Ok(entries)
+    }
+
+    pub fn each_below<F>(&self, key: &str, callback: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let key = key.as_bytes();
+        self.scan(|e, f| e.each_below_raw(key, f), callback)
+    }
+
+    pub fn each_between<F>(&self, key1: &str, key2: &str, callback: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let (key1, key2) = (key1.as_bytes(), key2.as_bytes());
+        self.scan(|e, f| e.each_between_raw(key1, key2, f), callback)
+    }
+}
@@ -0,0 +1,146 @@
+use crate::cache::CachedEngine;
+use crate::concurrent::ConcurrentEngine;
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use crate::range::Entry;
+use crate::shard::ShardedEngine;
+use std::ops::ControlFlow;
+use std::sync::{Mutex, MutexGuard};
+
+/// Point operations and short scans shared by engines and wrappers, so tools such as
+/// benchmark drivers run against any of them, or against other stores.
+///
+/// Every method takes `&self`, so a bare `KVEngine` is used through a `Mutex`, or as a
+/// `ConcurrentEngine` for the engines that support concurrent access.
+pub trait KvStore: Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<()>;
+
+    /// Fails with `ErrorKind::NotFound` if `key` is absent.
+    fn remove(&self, key: &str) -> Result<()>;
+
+    /// Up to `limit` pairs in key order, starting at `key` inclusive. Fails with
+    /// `ErrorKind::Unordered` on stores known to be hash-ordered, such as a
+    /// `ConcurrentEngine`; a bare `KVEngine` has to be an ordered engine.
+    fn scan(&self, key: &str, limit: usize) -> Result<Vec<Entry>>;
+}
+
+// `get_raw` reports no status, so a miss is checked with `exists`, which tells an
+// absent key from a failed read. The caller keeps writers out in between.
+fn get_checked(engine: &KVEngine, key: &str) -> Result<Option<Vec<u8>>> {
+    if let Some(value) = engine.get_copy_raw(key.as_bytes()) {
+        return Ok(Some(value));
+    }
+    match engine.exists(key) {
+        Err(Error(ErrorKind::NotFound(_), _)) => Ok(None),
+        Err(e) => Err(e),
+        Ok(()) => bail!(ErrorKind::Fail),
+    }
+}
+
+fn scan_raw(engine: &KVEngine, key: &[u8], limit: usize) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    if limit == 0 {
+        return entries;
+    }
+    engine.get_raw(key, &mut |v| entries.push((key.to_vec(), v.to_vec())));
+    if entries.len() < limit {
+        engine.each_above_raw(key, &mut |k, v| {
+            entries.push((k.to_vec(), v.to_vec()));
+            if entries.len() < limit {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        });
+    }
+    entries
+}
+
+impl KvStore for ConcurrentEngine {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        ConcurrentEngine::get(self, key)
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        ConcurrentEngine::put(self, key, value)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        ConcurrentEngine::remove(self, key)
+    }
+
+    /// The engines with concurrent access are all hash-ordered.
+    fn scan(&self, _key: &str, _limit: usize) -> Result<Vec<Entry>> {
+        bail!(ErrorKind::Unordered)
+    }
+}
+
+fn lock(engine: &Mutex<KVEngine>) -> MutexGuard<'_, KVEngine> {
+    engine.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl KvStore for Mutex<KVEngine> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        get_checked(&lock(self), key)
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        lock(self).put_raw(key.as_bytes(), value)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        lock(self).remove_raw(key.as_bytes())
+    }
+
+    fn scan(&self, key: &str, limit: usize) -> Result<Vec<Entry>> {
+        Ok(scan_raw(&lock(self), key.as_bytes(), limit))
+    }
+}
+
+impl KvStore for CachedEngine {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        CachedEngine::get(self, key)
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        CachedEngine::put(self, key, value)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        CachedEngine::remove(self, key)
+    }
+
+    /// Scans bypass the cache.
+    fn scan(&self, key: &str, limit: usize) -> Result<Vec<Entry>> {
+        Ok(scan_raw(&self.engine(), key.as_bytes(), limit))
+    }
+}
+
+impl KvStore for ShardedEngine {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        ShardedEngine::get(self, key)
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        ShardedEngine::put(self, key, value)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        ShardedEngine::remove(self, key)
+    }
+
+    fn scan(&self, key: &str, limit: usize) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        if limit == 0 {
+            return Ok(entries);
+        }
+        if let Some(value) = ShardedEngine::get(self, key)? {
+            entries.push((key.as_bytes().to_vec(), value));
+        }
+        let rest = self.take_above(key, limit - entries.len())?;
+        entries.extend(rest);
+        Ok(entries)
+    }
+}
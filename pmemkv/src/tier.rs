@@ -0,0 +1,279 @@
+use crate::errors::*;
+use crate::kvengine::{EngineRef, KVEngine, SharedEngine};
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    /// Writes go to both tiers before `put` returns.
+    WriteThrough,
+    /// Writes go to the hot tier only and reach the cold tier when the entry is
+    /// demoted or `flush` is called. Unflushed writes are lost if the process exits.
+    WriteBack,
+}
+
+struct HotEntry {
+    last_access: Instant,
+    size: usize,
+    dirty: bool,
+}
+
+#[derive(Default)]
+struct HotSet {
+    entries: HashMap<Vec<u8>, HotEntry>,
+    bytes: usize,
+}
+
+/// Two engines used as one: a fast, usually volatile hot tier in front of a persistent
+/// cold tier. Reads promote cold entries into the hot tier, and `demote` moves entries
+/// back out by age or when the hot tier exceeds its size budget. Both engines should
+/// only be accessed through the wrapper.
+pub struct TieredEngine {
+    hot: SharedEngine,
+    cold: SharedEngine,
+    policy: WritePolicy,
+    promote: bool,
+    max_age: Option<Duration>,
+    hot_bytes: Option<usize>,
+    // Tracks what the hot tier holds; held by every write, and taken before the
+    // engine locks.
+    state: Mutex<HotSet>,
+}
+
+impl TieredEngine {
+    pub fn new(hot: KVEngine, cold: KVEngine) -> TieredEngine {
+        TieredEngine {
+            hot: SharedEngine::new(hot),
+            cold: SharedEngine::new(cold),
+            policy: WritePolicy::WriteThrough,
+            promote: true,
+            max_age: None,
+            hot_bytes: None,
+            state: Mutex::new(HotSet::default()),
+        }
+    }
+
+    pub fn policy(mut self, policy: WritePolicy) -> TieredEngine {
+        self.policy = policy;
+        self
+    }
+
+    /// Whether reads served by the cold tier copy the entry into the hot tier.
+    pub fn promote_on_read(mut self, promote: bool) -> TieredEngine {
+        self.promote = promote;
+        self
+    }
+
+    /// Entries not accessed for `max_age` are demoted by `demote`.
+    pub fn max_age(mut self, max_age: Duration) -> TieredEngine {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Budget for the keys and values held by the hot tier. Least recently used
+    /// entries are demoted as soon as it is exceeded.
+    pub fn hot_bytes(mut self, hot_bytes: usize) -> TieredEngine {
+        self.hot_bytes = Some(hot_bytes);
+        self
+    }
+
+    /// Locks the hot tier for reads until the returned guard is dropped.
+    pub fn hot(&self) -> EngineRef<'_> {
+        self.hot.read()
+    }
+
+    /// Locks the cold tier for reads until the returned guard is dropped.
+    pub fn cold(&self) -> EngineRef<'_> {
+        self.cold.read()
+    }
+
+    pub fn into_inner(self) -> (KVEngine, KVEngine) {
+        (self.hot.into_inner(), self.cold.into_inner())
+    }
+
+    fn state(&self) -> MutexGuard<'_, HotSet> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn insert_hot(&self, state: &mut HotSet, key: &[u8], value: &[u8], dirty: bool) -> Result<()> {
+        self.hot.lock().put_raw(key, value)?;
+        let size = key.len() + value.len();
+        let previous = state.entries.insert(
+            key.to_vec(),
+            HotEntry {
+                last_access: Instant::now(),
+                size,
+                dirty,
+            },
+        );
+        state.bytes += size;
+        if let Some(previous) = previous {
+            state.bytes -= previous.size;
+        }
+        self.enforce_budget(state)
+    }
+
+    // Moves `key` to the cold tier, writing it there first if it is dirty.
+    fn demote_key(&self, state: &mut HotSet, key: &[u8]) -> Result<()> {
+        let dirty = match state.entries.get(key) {
+            Some(entry) => entry.dirty,
+            None => return Ok(()),
+        };
+        let mut hot = self.hot.lock();
+        if dirty {
+            if let Some(value) = hot.get_copy_raw(key) {
+                self.cold.lock().put_raw(key, &value)?;
+            }
+        }
+        match hot.remove_raw(key) {
+            Err(Error(ErrorKind::NotFound(_), _)) | Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+        if let Some(entry) = state.entries.remove(key) {
+            state.bytes -= entry.size;
+        }
+        Ok(())
+    }
+
+    fn enforce_budget(&self, state: &mut HotSet) -> Result<()> {
+        let budget = match self.hot_bytes {
+            Some(budget) if state.bytes > budget => budget,
+            _ => return Ok(()),
+        };
+        let mut by_age: Vec<(Instant, Vec<u8>)> = state
+            .entries
+            .iter()
+            .map(|(k, e)| (e.last_access, k.clone()))
+            .collect();
+        by_age.sort_unstable();
+        for (_, key) in by_age {
+            if state.bytes <= budget {
+                break;
+            }
+            self.demote_key(state, &key)?;
+        }
+        Ok(())
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        let key = key.as_bytes();
+        let mut state = self.state();
+        let dirty = match self.policy {
+            WritePolicy::WriteThrough => {
+                self.cold.lock().put_raw(key, value)?;
+                false
+            }
+            WritePolicy::WriteBack => true,
+        };
+        self.insert_hot(&mut state, key, value, dirty)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = key.as_bytes();
+        let mut state = self.state();
+        if let Some(entry) = state.entries.get_mut(key) {
+            entry.last_access = Instant::now();
+            return Ok(self.hot.lock().get_copy_raw(key));
+        }
+        let value = self.cold.lock().get_copy_raw(key);
+        if let (Some(v), true) = (&value, self.promote) {
+            self.insert_hot(&mut state, key, v, false)?;
+        }
+        Ok(value)
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        let key = key.as_bytes();
+        let state = self.state();
+        state.entries.contains_key(key) || self.cold.lock().exists_raw(key)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let bytes = key.as_bytes();
+        let mut state = self.state();
+        let in_hot = match state.entries.remove(bytes) {
+            Some(entry) => {
+                state.bytes -= entry.size;
+                self.hot.lock().remove_raw(bytes)?;
+                true
+            }
+            None => false,
+        };
+        let removed = self.cold.lock().remove_raw(bytes);
+        match removed {
+            Err(Error(ErrorKind::NotFound(_), _)) if in_hot => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Writes the dirty entries of the hot tier to the cold tier. Returns how many
+    /// were written.
+    pub fn flush(&self) -> Result<usize> {
+        let mut state = self.state();
+        let (hot, mut cold) = (self.hot.lock(), self.cold.lock());
+        let mut flushed = 0;
+        for (key, entry) in state.entries.iter_mut().filter(|(_, e)| e.dirty) {
+            if let Some(value) = hot.get_copy_raw(key) {
+                cold.put_raw(key, &value)?;
+            }
+            entry.dirty = false;
+            flushed += 1;
+        }
+        Ok(flushed)
+    }
+
+    /// Moves entries older than `max_age` out of the hot tier, then enforces the
+    /// `hot_bytes` budget. Returns how many entries were demoted.
+    pub fn demote(&self) -> Result<usize> {
+        let mut state = self.state();
+        let before = state.entries.len();
+        if let Some(max_age) = self.max_age {
+            let stale: Vec<Vec<u8>> = state
+                .entries
+                .iter()
+                .filter(|(_, e)| e.last_access.elapsed() >= max_age)
+                .map(|(k, _)| k.clone())
+                .collect();
+            for key in stale {
+                self.demote_key(&mut state, &key)?;
+            }
+        }
+        self.enforce_budget(&mut state)?;
+        Ok(before - state.entries.len())
+    }
+
+    /// Visits every pair once: the hot tier first, then the cold tier, skipping keys
+    /// also present in the hot tier, whose value is the current one. Pairs come in no
+    /// particular order, so the cold tier may be any engine. The hot tier, which the
+    /// budget keeps small, is copied first; `callback` must not call back into the
+    /// `TieredEngine`.
+    pub fn each<F>(&self, mut callback: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        let mut hot: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        self.hot.lock().each_raw(&mut |k, v| {
+            hot.insert(k.to_vec(), v.to_vec());
+            ControlFlow::Continue(())
+        });
+        for (k, v) in &hot {
+            callback(k, v);
+        }
+        self.cold.lock().each_raw(&mut |k, v| {
+            if !hot.contains_key(k) {
+                callback(k, v);
+            }
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Number of distinct keys over both tiers.
+    pub fn count(&self) -> i64 {
+        let state = self.state();
+        let cold = self.cold.lock();
+        let hot_only = state.entries.keys().filter(|k| !cold.exists_raw(k)).count();
+        cold.count() + hot_only as i64
+    }
+}
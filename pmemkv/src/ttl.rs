@@ -0,0 +1,246 @@
+use crate::errors::*;
+use crate::kvengine::{EngineRef, KVEngine, SharedEngine};
+use crate::scan::prefix_upper_bound;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Keys reserved for the expiry index: `EXPIRY_PREFIX + deadline + key`, with the
+/// deadline in big-endian milliseconds since the Unix epoch so due entries sort first.
+pub const EXPIRY_PREFIX: &[u8] = b"\xff\xfcpmemkv-ttl:";
+
+pub(crate) const NO_DEADLINE: u64 = 0;
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Stored values are the big-endian deadline followed by the user value.
+fn encode(deadline: u64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + value.len());
+    buf.extend_from_slice(&deadline.to_be_bytes());
+    buf.extend_from_slice(value);
+    buf
+}
+
+pub(crate) fn decode(buf: &[u8]) -> (u64, &[u8]) {
+    if buf.len() < 8 {
+        return (NO_DEADLINE, buf);
+    }
+    let (deadline, value) = buf.split_at(8);
+    let mut d = [0u8; 8];
+    d.copy_from_slice(deadline);
+    (u64::from_be_bytes(d), value)
+}
+
+fn index_key(deadline: u64, key: &[u8]) -> Vec<u8> {
+    let mut ik = EXPIRY_PREFIX.to_vec();
+    ik.extend_from_slice(&deadline.to_be_bytes());
+    ik.extend_from_slice(key);
+    ik
+}
+
+fn expired(deadline: u64, now: u64) -> bool {
+    deadline != NO_DEADLINE && deadline <= now
+}
+
+/// Engine wrapper adding per-key expiration. Expired keys are hidden from reads at
+/// once and removed either lazily when read or by `purge_expired`. Requires an ordered
+/// engine, which should only be accessed through the wrapper.
+pub struct TtlEngine {
+    // Locked by every access, which serializes writers against readers and the
+    // reaper.
+    engine: SharedEngine,
+}
+
+/// Background reaper started by `TtlEngine::start_reaper`. Dropping the handle stops
+/// the thread.
+pub struct ReaperHandle {
+    purged: Arc<AtomicUsize>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TtlEngine {
+    pub fn new(engine: KVEngine) -> TtlEngine {
+        TtlEngine {
+            engine: SharedEngine::new(engine),
+        }
+    }
+
+    /// Locks the engine for reads until the returned guard is dropped.
+    pub fn engine(&self) -> EngineRef<'_> {
+        self.engine.read()
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine.into_inner()
+    }
+
+    // Writes `key` with `deadline`, replacing its previous expiry index entry.
+    fn write(&self, key: &[u8], value: &[u8], deadline: u64) -> Result<()> {
+        let mut engine = self.engine.lock();
+        if let Some(old) = engine.get_copy_raw(key) {
+            let (old_deadline, _) = decode(&old);
+            if old_deadline != NO_DEADLINE && old_deadline != deadline {
+                let _ = engine.remove_raw(&index_key(old_deadline, key));
+            }
+        }
+        if deadline != NO_DEADLINE {
+            engine.put_raw(&index_key(deadline, key), &[])?;
+        }
+        engine.put_raw(key, &encode(deadline, value))
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.write(key.as_bytes(), value, NO_DEADLINE)
+    }
+
+    /// Stores `value` under `key` until `ttl` has elapsed.
+    pub fn put_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        let deadline = now_millis().saturating_add(ttl.as_millis() as u64).max(1);
+        self.write(key.as_bytes(), value, deadline)
+    }
+
+    // Removes `key` and its index entry if it still has `deadline`.
+    fn expire(&self, key: &[u8], deadline: u64) -> Result<bool> {
+        let mut engine = self.engine.lock();
+        let _ = engine.remove_raw(&index_key(deadline, key));
+        match engine.get_copy_raw(key) {
+            Some(ref buf) if decode(buf).0 == deadline => {
+                engine.remove_raw(key)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Reads `key`, expiring it on the spot if its deadline has passed.
+    fn read(&self, key: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+        let stored = self.engine.lock().get_copy_raw(key);
+        let buf = match stored {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+        let (deadline, value) = decode(&buf);
+        if expired(deadline, now_millis()) {
+            self.expire(key, deadline)?;
+            return Ok(None);
+        }
+        Ok(Some((deadline, value.to_vec())))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.read(key.as_bytes())?.map(|(_, value)| value))
+    }
+
+    pub fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.read(key.as_bytes())?.is_some())
+    }
+
+    /// Time left before `key` expires, or `None` if it has no expiry.
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        match self.read(key.as_bytes())? {
+            Some((NO_DEADLINE, _)) => Ok(None),
+            Some((deadline, _)) => Ok(Some(Duration::from_millis(
+                deadline.saturating_sub(now_millis()),
+            ))),
+            None => Err(ErrorKind::NotFound(key.to_string()).into()),
+        }
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let key = key.as_bytes();
+        let mut engine = self.engine.lock();
+        if let Some(buf) = engine.get_copy_raw(key) {
+            let (deadline, _) = decode(&buf);
+            if deadline != NO_DEADLINE {
+                let _ = engine.remove_raw(&index_key(deadline, key));
+            }
+        }
+        engine.remove_raw(key)
+    }
+
+    /// Removes every key whose deadline has passed, found through the expiry index.
+    /// Returns how many keys were purged.
+    pub fn purge_expired(&self) -> Result<usize> {
+        let now = now_millis();
+        let mut due = Vec::new();
+        {
+            let engine = self.engine.lock();
+            let upper = index_key(now.saturating_add(1), &[]);
+            engine.each_between_raw(EXPIRY_PREFIX, &upper, &mut |ik, _| {
+                let rest = &ik[EXPIRY_PREFIX.len()..];
+                if rest.len() >= 8 {
+                    let (deadline, key) = decode(rest);
+                    due.push((deadline, key.to_vec()));
+                }
+                ControlFlow::Continue(())
+            });
+        }
+        let mut purged = 0;
+        for (deadline, key) in due {
+            if self.expire(&key, deadline)? {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Runs `purge_expired` on a background thread every `interval`.
+    pub fn start_reaper(db: &Arc<TtlEngine>, interval: Duration) -> ReaperHandle {
+        let (stop, stopped) = mpsc::channel();
+        let purged = Arc::new(AtomicUsize::new(0));
+        let thread = {
+            let db = Arc::clone(db);
+            let purged = Arc::clone(&purged);
+            thread::spawn(move || loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Ok(n) = db.purge_expired() {
+                            purged.fetch_add(n, Ordering::Relaxed);
+                        }
+                    }
+                    _ => return,
+                }
+            })
+        };
+        ReaperHandle {
+            purged,
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Number of keys stored, including expired ones not purged yet.
+    pub fn count(&self) -> i64 {
+        let engine = self.engine.lock();
+        let index = match prefix_upper_bound(EXPIRY_PREFIX) {
+            Some(upper) => engine.count_between_raw(EXPIRY_PREFIX, &upper),
+            None => 0,
+        };
+        engine.count() - index
+    }
+}
+
+impl ReaperHandle {
+    /// Total number of keys purged by the reaper so far.
+    pub fn purged(&self) -> usize {
+        self.purged.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ReaperHandle {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
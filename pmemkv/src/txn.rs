@@ -0,0 +1,151 @@
+use crate::batch::WriteBatch;
+use crate::errors::*;
+use crate::kvengine::{EngineRef, KVEngine, SharedEngine};
+use crate::sequence;
+use std::collections::{BTreeMap, HashMap};
+
+// Values written through `TxnEngine` carry a version stamp: a magic, the big-endian
+// commit sequence number and the big-endian length of the value that follows. Values
+// without a stamp count as version 0; one is only mistaken for a stamped value if it
+// starts with the magic and its length matches the length field too.
+const STAMP_MAGIC: &[u8] = b"\xA5PTX";
+const STAMP_LEN: usize = 16;
+
+fn stamp(version: u64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(STAMP_LEN + value.len());
+    buf.extend_from_slice(STAMP_MAGIC);
+    buf.extend_from_slice(&version.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf
+}
+
+fn unstamp(buf: &[u8]) -> (u64, &[u8]) {
+    if buf.len() >= STAMP_LEN && buf.starts_with(STAMP_MAGIC) {
+        let mut version = [0u8; 8];
+        version.copy_from_slice(&buf[4..12]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&buf[12..STAMP_LEN]);
+        if u32::from_be_bytes(len) as usize == buf.len() - STAMP_LEN {
+            return (u64::from_be_bytes(version), &buf[STAMP_LEN..]);
+        }
+    }
+    (0, buf)
+}
+
+fn read(engine: &KVEngine, key: &[u8]) -> Option<(u64, Vec<u8>)> {
+    engine.get_copy_raw(key).map(|buf| {
+        let (version, value) = unstamp(&buf);
+        (version, value.to_vec())
+    })
+}
+
+/// Engine wrapper providing optimistic multi-key transactions. Every value is stored
+/// with a version stamp, so the underlying engine should only be written through it.
+/// Commits hold the engine lock while they validate and apply.
+pub struct TxnEngine {
+    engine: SharedEngine,
+}
+
+/// A transaction started by `TxnEngine::begin`. Reads are recorded with the version
+/// they observed and writes are buffered until `commit`.
+pub struct Transaction<'a> {
+    db: &'a TxnEngine,
+    // Version observed by the first read of each key; `None` if the key was absent.
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl TxnEngine {
+    /// Wraps `engine`, first finishing any commit interrupted by a crash.
+    pub fn new(mut engine: KVEngine) -> Result<TxnEngine> {
+        WriteBatch::recover(&mut engine)?;
+        Ok(TxnEngine {
+            engine: SharedEngine::new(engine),
+        })
+    }
+
+    /// Locks the engine for reads until the returned guard is dropped.
+    pub fn engine(&self) -> EngineRef<'_> {
+        self.engine.read()
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine.into_inner()
+    }
+
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction {
+            db: self,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Reads the latest committed value of `key` outside of any transaction.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(read(&self.engine.lock(), key.as_bytes()).map(|(_, value)| value))
+    }
+}
+
+impl<'a> Transaction<'a> {
+    /// Reads `key`, seeing this transaction's own writes.
+    pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = key.as_bytes();
+        if let Some(write) = self.writes.get(key) {
+            return Ok(write.clone());
+        }
+        let current = read(&self.db.engine.lock(), key);
+        self.reads
+            .entry(key.to_vec())
+            .or_insert_with(|| current.as_ref().map(|(version, _)| *version));
+        Ok(current.map(|(_, value)| value))
+    }
+
+    pub fn put(&mut self, key: &str, value: &[u8]) {
+        self.writes
+            .insert(key.as_bytes().to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.writes.insert(key.as_bytes().to_vec(), None);
+    }
+
+    /// Validates that nothing this transaction read has changed since, then applies
+    /// its writes through a crash-atomic batch. Fails with `ErrorKind::Conflict` if
+    /// another commit got in first; the caller may retry with a new transaction.
+    /// Commits from several threads sharing the `TxnEngine` are serialized by the
+    /// engine lock, so any engine can back it.
+    pub fn commit(self) -> Result<()> {
+        let mut engine = self.db.engine.lock();
+        for (key, seen) in &self.reads {
+            let now = read(&engine, key).map(|(version, _)| version);
+            if now != *seen {
+                bail!(ErrorKind::Conflict(
+                    String::from_utf8_lossy(key).into_owned()
+                ));
+            }
+        }
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let version = sequence::next();
+        let mut batch = WriteBatch::new();
+        for (key, write) in &self.writes {
+            match write {
+                Some(value) => batch.put_bytes(key, &stamp(version, value)),
+                None => batch.remove_bytes(key),
+            };
+        }
+        for status in batch.apply_atomic(&mut engine)? {
+            match status {
+                Err(Error(ErrorKind::NotFound(_), _)) | Ok(()) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the buffered writes.
+    pub fn rollback(self) {}
+}
@@ -0,0 +1,114 @@
+use crate::errors::*;
+use crate::kvengine::KVEngine;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+const LOCK_STRIPES: usize = 256;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNLOCKED: Mutex<()> = Mutex::new(());
+
+// Striped lock table shared by every engine handle in the process. A key maps to one
+// stripe per engine, so read-modify-write operations on the same key are serialized
+// while unrelated keys rarely contend.
+static KEY_LOCKS: [Mutex<()>; LOCK_STRIPES] = [UNLOCKED; LOCK_STRIPES];
+
+fn stripe(engine: &KVEngine, key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    (engine.as_ptr() as usize).hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish() as usize % LOCK_STRIPES
+}
+
+fn lock_stripe(stripe: usize) -> MutexGuard<'static, ()> {
+    KEY_LOCKS[stripe]
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub(crate) fn lock_key(engine: &KVEngine, key: &[u8]) -> MutexGuard<'static, ()> {
+    lock_stripe(stripe(engine, key))
+}
+
+// Single-key read-modify-write. Through `&mut self` nothing else can access the
+// engine in between; `ConcurrentEngine` provides the same operations through `&self`,
+// serialized by the key lock.
+impl KVEngine {
+    /// Replaces the value of `key` with the result of `f`, which receives the current
+    /// value (`None` when the key is absent). Returning `None` removes the key.
+    /// Returns the value written.
+    pub fn update<F>(&mut self, key: &str, f: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        self.update_raw(key.as_bytes(), f)
+    }
+
+    pub(crate) fn update_raw<F>(&mut self, key: &[u8], f: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        unsafe { self.update_shared(key, f) }
+    }
+
+    /// Sets `key` to `new` only if its current value equals `expected`, where `None`
+    /// stands for an absent key on both sides. Returns whether the swap happened.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        unsafe { self.compare_and_swap_shared(key.as_bytes(), expected, new) }
+    }
+
+    /// Stores `value` under `key` and returns the value it replaced.
+    pub fn get_and_put(&mut self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        unsafe { self.get_and_put_shared(key.as_bytes(), value) }
+    }
+
+    // The caller guarantees, as for `put_shared`, that no other write to `key` can
+    // interleave.
+    pub(crate) unsafe fn update_shared<F>(&self, key: &[u8], f: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let current = self.get_copy_raw(key);
+        let new = f(current.as_deref());
+        match new {
+            Some(ref value) => self.put_shared(key, value)?,
+            None if current.is_some() => self.remove_shared(key)?,
+            None => {}
+        }
+        Ok(new)
+    }
+
+    pub(crate) unsafe fn compare_and_swap_shared(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let current = self.get_copy_raw(key);
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.put_shared(key, value)?,
+            None if current.is_some() => self.remove_shared(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    pub(crate) unsafe fn get_and_put_shared(
+        &self,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let previous = self.get_copy_raw(key);
+        self.put_shared(key, value)?;
+        Ok(previous)
+    }
+}
@@ -0,0 +1,379 @@
+use crate::batch::{self, JOURNAL_PREFIX};
+use crate::integrity;
+use crate::kvengine::KVEngine;
+use crate::merge::OPERAND_PREFIX;
+use crate::migrate::CHECKPOINT_KEY;
+use crate::ttl::{self, EXPIRY_PREFIX, NO_DEADLINE};
+use std::collections::BTreeSet;
+use std::ops::ControlFlow;
+
+const DEFAULT_SAMPLES: usize = 16;
+
+/// Check that found an `Issue`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Check {
+    /// `count` disagrees with the number of pairs visited by `each`.
+    Count,
+    /// `count_above`, `count_below` or `count_between` disagrees with the scan.
+    Range,
+    /// A key does not sort after the one visited before it.
+    Order,
+    /// A value fails its `IntegrityEngine` checksum.
+    Checksum,
+    /// A key and the `TtlEngine` expiry index disagree.
+    Ttl,
+    /// A key in the reserved `0xFF` range is malformed or left over by an
+    /// interrupted operation.
+    Namespace,
+}
+
+impl Check {
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::Count => "count",
+            Check::Range => "range",
+            Check::Order => "order",
+            Check::Checksum => "checksum",
+            Check::Ttl => "ttl",
+            Check::Namespace => "namespace",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub check: Check,
+    /// Key the issue was found at, if it concerns a single key.
+    pub key: Option<Vec<u8>>,
+    pub detail: String,
+}
+
+/// Keys found in each of the reserved namespaces.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Namespaces {
+    pub batch_journal: u64,
+    pub merge_operands: u64,
+    pub expiry_index: u64,
+    pub migrate_checkpoint: u64,
+    pub unknown: u64,
+}
+
+/// Outcome of `Verifier::run`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Pair count reported by the engine.
+    pub count: i64,
+    /// Pairs visited by a full scan, reserved keys included.
+    pub visited: u64,
+    /// Range counts compared against the scan.
+    pub ranges_checked: u64,
+    /// Values whose checksum was verified.
+    pub values_checked: u64,
+    pub namespaces: Namespaces,
+    /// Problems found, in scan order for those tied to a key.
+    pub issues: Vec<Issue>,
+}
+
+impl VerifyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// The report as a JSON object. Keys are given as `key` when they are UTF-8 and
+    /// as `key_hex` otherwise, as in dumps.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        let issues: Vec<serde_json::Value> = self
+            .issues
+            .iter()
+            .map(|issue| {
+                let mut object = serde_json::Map::new();
+                object.insert("check".to_string(), issue.check.name().into());
+                if let Some(ref key) = issue.key {
+                    crate::dump::json_field(&mut object, "key", key);
+                }
+                object.insert("detail".to_string(), issue.detail.clone().into());
+                object.into()
+            })
+            .collect();
+        let ns = &self.namespaces;
+        serde_json::json!({
+            "consistent": self.is_consistent(),
+            "count": self.count,
+            "visited": self.visited,
+            "ranges_checked": self.ranges_checked,
+            "values_checked": self.values_checked,
+            "namespaces": {
+                "batch_journal": ns.batch_journal,
+                "merge_operands": ns.merge_operands,
+                "expiry_index": ns.expiry_index,
+                "migrate_checkpoint": ns.migrate_checkpoint,
+                "unknown": ns.unknown,
+            },
+            "issues": issues,
+        })
+    }
+}
+
+// State gathered by the scan and checked once it is over.
+#[derive(Default)]
+struct Scan {
+    report: VerifyReport,
+    previous: Option<Vec<u8>>,
+    unordered: bool,
+    // Sampled keys and their position in the scan.
+    samples: Vec<(u64, Vec<u8>)>,
+    next_sample: u64,
+    // Deadlines found in values and in the expiry index, with their keys.
+    deadlines: BTreeSet<(u64, Vec<u8>)>,
+    indexed: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl Scan {
+    fn issue(&mut self, check: Check, key: Option<&[u8]>, detail: String) {
+        self.report.issues.push(Issue {
+            check,
+            key: key.map(|k| k.to_vec()),
+            detail,
+        });
+    }
+
+    fn compare(&mut self, key: &[u8], range: &str, counted: i64, scanned: u64) {
+        self.report.ranges_checked += 1;
+        if counted < 0 || counted as u64 != scanned {
+            let detail = format!("{} is {}, the scan visited {}", range, counted, scanned);
+            self.issue(Check::Range, Some(key), detail);
+        }
+    }
+}
+
+/// Consistency checker for an engine and the metadata the wrappers keep in it. It
+/// reads the engine only, so run it while nothing else writes to it.
+///
+/// A full scan is compared against `count`, and on ordered engines checked for key
+/// order and against `count_above`, `count_below` and `count_between` around sampled
+/// keys. Keys in the reserved `0xFF` range must belong to a known namespace, and
+/// batch journals or migration checkpoints left by interrupted operations are
+/// reported. Checksums and the expiry index are checked on request.
+pub struct Verifier<'a> {
+    engine: &'a KVEngine,
+    ordered: bool,
+    samples: usize,
+    checksums: bool,
+    ttl: bool,
+    namespaces: bool,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(engine: &'a KVEngine) -> Verifier<'a> {
+        Verifier {
+            engine,
+            ordered: true,
+            samples: DEFAULT_SAMPLES,
+            checksums: false,
+            ttl: false,
+            namespaces: true,
+        }
+    }
+
+    /// Whether the engine keeps keys in order, as `vsmap` or `stree` do. Turn it off
+    /// for `cmap` and the other hash engines to skip the order and range checks.
+    pub fn ordered(mut self, ordered: bool) -> Verifier<'a> {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Number of keys, spread over the scan, around which range counts are checked.
+    pub fn samples(mut self, samples: usize) -> Verifier<'a> {
+        self.samples = samples;
+        self
+    }
+
+    /// Verifies the checksum of every value, for engines written by `IntegrityEngine`.
+    pub fn checksums(mut self, checksums: bool) -> Verifier<'a> {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Cross-checks value deadlines and the expiry index, for engines written by
+    /// `TtlEngine`.
+    pub fn ttl(mut self, ttl: bool) -> Verifier<'a> {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Whether to check the reserved namespaces. Turn it off for engines whose keys
+    /// are encrypted by `EncryptedEngine`, which may start with `0xFF`.
+    pub fn namespaces(mut self, namespaces: bool) -> Verifier<'a> {
+        self.namespaces = namespaces;
+        self
+    }
+
+    fn visit(&self, scan: &mut Scan, key: &[u8], value: &[u8]) {
+        let position = scan.report.visited;
+        scan.report.visited += 1;
+        if self.ordered {
+            if let Some(ref previous) = scan.previous {
+                if key <= &previous[..] {
+                    scan.unordered = true;
+                    let detail = if key == &previous[..] {
+                        "key visited twice"
+                    } else {
+                        "key sorts before the previous key"
+                    };
+                    scan.issue(Check::Order, Some(key), detail.to_string());
+                }
+            }
+            let previous = scan.previous.get_or_insert_with(Vec::new);
+            previous.clear();
+            previous.extend_from_slice(key);
+            if position == scan.next_sample && scan.samples.len() < self.samples {
+                scan.samples.push((position, key.to_vec()));
+                let stride = scan.report.count.max(0) as u64 / self.samples.max(1) as u64;
+                scan.next_sample += stride.max(1);
+            }
+        }
+        if self.namespaces && key.first() == Some(&0xff) {
+            self.visit_reserved(scan, key, value);
+            return;
+        }
+        if self.checksums {
+            scan.report.values_checked += 1;
+            if integrity::verify(value).is_none() {
+                scan.issue(Check::Checksum, Some(key), "checksum mismatch".to_string());
+            }
+        }
+        if self.ttl {
+            if value.len() < 8 {
+                scan.issue(
+                    Check::Ttl,
+                    Some(key),
+                    "value shorter than its deadline".to_string(),
+                );
+            } else {
+                let (deadline, _) = ttl::decode(value);
+                if deadline != NO_DEADLINE {
+                    scan.deadlines.insert((deadline, key.to_vec()));
+                }
+            }
+        }
+    }
+
+    fn visit_reserved(&self, scan: &mut Scan, key: &[u8], value: &[u8]) {
+        let namespaces = &mut scan.report.namespaces;
+        if key.starts_with(JOURNAL_PREFIX) {
+            namespaces.batch_journal += 1;
+            if batch::parse_journal_key(key).is_none() {
+                let detail = "malformed batch journal key".to_string();
+                scan.issue(Check::Namespace, Some(key), detail);
+            }
+        } else if key.starts_with(OPERAND_PREFIX) {
+            // The length of the target key, the key, then an 8-byte sequence number.
+            namespaces.merge_operands += 1;
+            let rest = &key[OPERAND_PREFIX.len()..];
+            let well_formed = rest.len() >= 4 && {
+                let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                rest.len() == 4 + len + 8
+            };
+            if !well_formed {
+                let detail = "malformed merge operand key".to_string();
+                scan.issue(Check::Namespace, Some(key), detail);
+            }
+        } else if key.starts_with(EXPIRY_PREFIX) {
+            namespaces.expiry_index += 1;
+            if self.ttl {
+                let rest = &key[EXPIRY_PREFIX.len()..];
+                if rest.len() < 8 || !value.is_empty() {
+                    let detail = "malformed expiry index entry".to_string();
+                    scan.issue(Check::Ttl, Some(key), detail);
+                } else {
+                    let (deadline, key) = ttl::decode(rest);
+                    scan.indexed.insert((deadline, key.to_vec()));
+                }
+            }
+        } else if key == CHECKPOINT_KEY {
+            namespaces.migrate_checkpoint += 1;
+            let detail = "checkpoint of an interrupted migration".to_string();
+            scan.issue(Check::Namespace, Some(key), detail);
+        } else {
+            namespaces.unknown += 1;
+            let detail = "reserved key outside the known namespaces".to_string();
+            scan.issue(Check::Namespace, Some(key), detail);
+        }
+    }
+
+    // Compares the range counts around the sampled keys with their scan positions.
+    fn check_ranges(&self, scan: &mut Scan) {
+        let samples = std::mem::take(&mut scan.samples);
+        let visited = scan.report.visited;
+        if let (Some((first, first_key)), Some((last, last_key))) =
+            (samples.first(), samples.last())
+        {
+            let counted = self.engine.count_below_raw(first_key);
+            scan.compare(first_key, "count_below", counted, *first);
+            let counted = self.engine.count_above_raw(last_key);
+            scan.compare(last_key, "count_above", counted, visited - last - 1);
+        }
+        for pair in samples.windows(2) {
+            let ((from, from_key), (to, to_key)) = (&pair[0], &pair[1]);
+            let counted = self.engine.count_between_raw(from_key, to_key);
+            scan.compare(
+                from_key,
+                "count_between up to the next sample",
+                counted,
+                to - from - 1,
+            );
+        }
+    }
+
+    // Every deadline stored with a value needs an index entry, and every index entry
+    // a value with that deadline.
+    fn check_expiry_index(&self, scan: &mut Scan) {
+        let deadlines = std::mem::take(&mut scan.deadlines);
+        let indexed = std::mem::take(&mut scan.indexed);
+        for (deadline, key) in deadlines.difference(&indexed) {
+            let detail = format!("no expiry index entry for deadline {}", deadline);
+            scan.issue(Check::Ttl, Some(key), detail);
+        }
+        for (deadline, key) in indexed.difference(&deadlines) {
+            let detail = format!("expiry index entry for deadline {} has no value", deadline);
+            scan.issue(Check::Ttl, Some(key), detail);
+        }
+    }
+
+    pub fn run(&self) -> VerifyReport {
+        let mut scan = Scan::default();
+        scan.report.count = self.engine.count();
+        self.engine.each_raw(&mut |k, v| {
+            self.visit(&mut scan, k, v);
+            ControlFlow::Continue(())
+        });
+        if scan.report.count < 0 || scan.report.count as u64 != scan.report.visited {
+            let detail = format!(
+                "count is {}, each visited {} pairs",
+                scan.report.count, scan.report.visited
+            );
+            scan.issue(Check::Count, None, detail);
+        }
+        // Range counts only mean something if the scan was in order.
+        if self.ordered && !scan.unordered {
+            self.check_ranges(&mut scan);
+        }
+        if self.ttl {
+            self.check_expiry_index(&mut scan);
+        }
+        if scan.report.namespaces.batch_journal > 0 {
+            let detail = format!(
+                "{} batch journal entries left by interrupted batches, run WriteBatch::recover",
+                scan.report.namespaces.batch_journal
+            );
+            scan.issue(Check::Namespace, None, detail);
+        }
+        scan.report
+    }
+}
+
+/// Checks an ordered engine with the default settings.
+pub fn verify(engine: &KVEngine) -> VerifyReport {
+    Verifier::new(engine).run()
+}
@@ -0,0 +1,493 @@
+use crate::errors::*;
+use crate::kvengine::{EngineRef, KVEngine, SharedEngine};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const WAL_MAGIC: &[u8] = b"PMKVWAL\x01";
+const SNAPSHOT_MAGIC: &[u8] = b"PMKVSNP\x01";
+const WAL_FILE: &str = "wal";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+const DEFAULT_SNAPSHOT_BYTES: u64 = 64 << 20;
+
+const TAG_PUT: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+
+/// When appended WAL records are forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Every write is synced before it returns.
+    EveryWrite,
+    /// Every write is synced before it returns, but writers arriving while a sync is
+    /// in flight share the next one.
+    GroupCommit,
+    /// Writes return once appended; a background thread syncs the log at this
+    /// interval, so a crash may lose the most recent writes.
+    Interval(Duration),
+}
+
+enum Record<'a> {
+    Put(&'a [u8], &'a [u8]),
+    Remove(&'a [u8]),
+}
+
+// Record layout: payload length and CRC-32C of the payload, both little-endian u32,
+// then the payload: a tag byte, the key length as u32, the key and the value.
+fn encode(record: &Record) -> Vec<u8> {
+    let (tag, key, value): (u8, &[u8], &[u8]) = match *record {
+        Record::Put(k, v) => (TAG_PUT, k, v),
+        Record::Remove(k) => (TAG_REMOVE, k, &[]),
+    };
+    let len = 5 + key.len() + value.len();
+    let mut buf = Vec::with_capacity(8 + len);
+    buf.extend_from_slice(&(len as u32).to_le_bytes());
+    buf.extend_from_slice(&[0; 4]);
+    buf.push(tag);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    let crc = crc32c::crc32c(&buf[8..]);
+    buf[4..8].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+fn decode(payload: &[u8]) -> Option<Record<'_>> {
+    if payload.len() < 5 {
+        return None;
+    }
+    let len = u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]]) as usize;
+    let rest = &payload[5..];
+    if rest.len() < len {
+        return None;
+    }
+    let (key, value) = rest.split_at(len);
+    match payload[0] {
+        TAG_PUT => Some(Record::Put(key, value)),
+        TAG_REMOVE if value.is_empty() => Some(Record::Remove(key)),
+        _ => None,
+    }
+}
+
+// Applies the records of `buf` that follow `magic` and returns the length of the
+// valid prefix of the file. Stops at the first torn or damaged record.
+fn replay(engine: &mut KVEngine, buf: &[u8], magic: &[u8]) -> Result<usize> {
+    if !buf.starts_with(magic) {
+        return Ok(0);
+    }
+    let mut pos = magic.len();
+    while buf.len() - pos >= 8 {
+        let header = &buf[pos..pos + 8];
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let payload = match buf.get(pos + 8..pos + 8 + len) {
+            Some(payload) if crc32c::crc32c(payload) == crc => payload,
+            _ => break,
+        };
+        match decode(payload) {
+            Some(Record::Put(k, v)) => engine.put_raw(k, v)?,
+            Some(Record::Remove(k)) => match engine.remove_raw(k) {
+                Err(Error(ErrorKind::NotFound(_), _)) | Ok(()) => {}
+                Err(e) => return Err(e),
+            },
+            None => break,
+        }
+        pos += 8 + len;
+    }
+    Ok(pos)
+}
+
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match File::open(path) {
+        Ok(file) => {
+            let mut buf = Vec::new();
+            BufReader::new(file).read_to_end(&mut buf)?;
+            Ok(Some(buf))
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+struct Wal {
+    file: File,
+    len: u64,
+}
+
+// Tracks how far the log is known to be on stable storage, counted in records.
+struct Syncer {
+    file: File,
+    written: AtomicU64,
+    state: Mutex<SyncState>,
+    synced_cv: Condvar,
+}
+
+#[derive(Default)]
+struct SyncState {
+    synced: u64,
+    syncing: bool,
+}
+
+impl Syncer {
+    // Returns once record `lsn` is synced. The first writer to find no sync in flight
+    // syncs everything written so far on behalf of the others.
+    fn wait(&self, lsn: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if state.synced >= lsn {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self
+                    .synced_cv
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+            state.syncing = true;
+            let target = self.written.load(Ordering::Acquire);
+            drop(state);
+            let result = self.file.sync_data();
+            state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced_cv.notify_all();
+            result?;
+        }
+    }
+}
+
+struct Flusher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Durability layer for volatile engines such as `vsmap` and `vcmap`. Every write is
+/// appended to a checksummed log in `dir` before it is applied; the log is folded
+/// into a snapshot once it grows past a threshold, and `open` rebuilds the engine from
+/// the snapshot and the log. The engine should only be written through the wrapper.
+pub struct DurableEngine {
+    engine: SharedEngine,
+    dir: PathBuf,
+    policy: SyncPolicy,
+    snapshot_bytes: u64,
+    // Writers hold the lock while they append and apply, so the log and the engine
+    // see writes in the same order. Taken before the engine lock.
+    wal: Mutex<Wal>,
+    syncer: Arc<Syncer>,
+    _flusher: Option<Flusher>,
+}
+
+impl DurableEngine {
+    /// Loads the snapshot and the log found in `dir`, if any, into `engine`, which
+    /// should be empty, and starts logging to `dir`. Fails with `InvalidFormat` if
+    /// either file is not one written by `DurableEngine`.
+    pub fn open<P: AsRef<Path>>(
+        mut engine: KVEngine,
+        dir: P,
+        policy: SyncPolicy,
+    ) -> Result<DurableEngine> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        if let Some(buf) = read_file(&snapshot_path)? {
+            // Snapshots are synced before they are renamed into place, so a damaged
+            // one is not a torn write.
+            if replay(&mut engine, &buf, SNAPSHOT_MAGIC)? != buf.len() {
+                bail!(ErrorKind::InvalidFormat(
+                    snapshot_path.display().to_string()
+                ));
+            }
+        }
+        let wal_path = dir.join(WAL_FILE);
+        let valid = match read_file(&wal_path)? {
+            // Cut short while its header was written, so it holds no records.
+            Some(ref buf) if WAL_MAGIC.starts_with(buf) => 0,
+            Some(ref buf) if !buf.starts_with(WAL_MAGIC) => {
+                bail!(ErrorKind::InvalidFormat(wal_path.display().to_string()))
+            }
+            Some(buf) => replay(&mut engine, &buf, WAL_MAGIC)?,
+            None => 0,
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&wal_path)?;
+        // Drop a torn tail so new records are not appended after garbage.
+        let len = if valid == 0 {
+            file.set_len(0)?;
+            file.write_all(WAL_MAGIC)?;
+            WAL_MAGIC.len() as u64
+        } else {
+            file.set_len(valid as u64)?;
+            valid as u64
+        };
+        file.sync_data()?;
+        let syncer = Arc::new(Syncer {
+            file: file.try_clone()?,
+            written: AtomicU64::new(0),
+            state: Mutex::new(SyncState::default()),
+            synced_cv: Condvar::new(),
+        });
+        let flusher = match policy {
+            SyncPolicy::Interval(interval) => Some(DurableEngine::start_flusher(&syncer, interval)),
+            _ => None,
+        };
+        Ok(DurableEngine {
+            engine: SharedEngine::new(engine),
+            dir,
+            policy,
+            snapshot_bytes: DEFAULT_SNAPSHOT_BYTES,
+            wal: Mutex::new(Wal { file, len }),
+            syncer,
+            _flusher: flusher,
+        })
+    }
+
+    /// Starts a volatile engine and restores it from `dir` with `open`.
+    pub fn start<P: AsRef<Path>>(
+        engine: &str,
+        config: &str,
+        dir: P,
+        policy: SyncPolicy,
+    ) -> Result<DurableEngine> {
+        let engine = KVEngine::start_string(engine, config, None::<fn(&str, &str, &str)>)?;
+        DurableEngine::open(engine, dir, policy)
+    }
+
+    /// Log size past which a write triggers a snapshot.
+    pub fn snapshot_bytes(mut self, bytes: u64) -> DurableEngine {
+        self.snapshot_bytes = bytes;
+        self
+    }
+
+    /// Locks the engine for reads until the returned guard is dropped.
+    pub fn engine(&self) -> EngineRef<'_> {
+        self.engine.read()
+    }
+
+    pub fn into_inner(self) -> KVEngine {
+        self.engine.into_inner()
+    }
+
+    fn wal(&self) -> MutexGuard<'_, Wal> {
+        self.wal.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    fn start_flusher(syncer: &Arc<Syncer>, interval: Duration) -> Flusher {
+        let (stop, stopped) = mpsc::channel();
+        let syncer = Arc::clone(syncer);
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = syncer.wait(syncer.written.load(Ordering::Acquire));
+                }
+                _ => return,
+            }
+        });
+        Flusher {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    fn log<F>(&self, record: Record, apply: F) -> Result<()>
+    where
+        F: FnOnce(&mut KVEngine) -> Result<()>,
+    {
+        let lsn = {
+            let mut wal = self.wal();
+            // Checked under the log lock, which every write holds, so the key cannot be
+            // removed by another writer before this record is applied.
+            if let Record::Remove(key) = record {
+                if !self.engine.lock().exists_raw(key) {
+                    bail!(ErrorKind::NotFound(
+                        String::from_utf8_lossy(key).into_owned()
+                    ));
+                }
+            }
+            let buf = encode(&record);
+            wal.file.write_all(&buf)?;
+            wal.len += buf.len() as u64;
+            let lsn = self.syncer.written.fetch_add(1, Ordering::AcqRel) + 1;
+            if self.policy == SyncPolicy::EveryWrite {
+                self.syncer.wait(lsn)?;
+            }
+            apply(&mut self.engine.lock())?;
+            if wal.len >= self.snapshot_bytes {
+                self.write_snapshot(&mut wal)?;
+            }
+            lsn
+        };
+        match self.policy {
+            SyncPolicy::GroupCommit => self.syncer.wait(lsn),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        let key = key.as_bytes();
+        self.log(Record::Put(key, value), |e| e.put_raw(key, value))
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let key = key.as_bytes();
+        self.log(Record::Remove(key), |e| e.remove_raw(key))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.engine.lock().get_copy_raw(key.as_bytes()))
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.engine.lock().exists_raw(key.as_bytes())
+    }
+
+    pub fn count(&self) -> i64 {
+        self.engine.lock().count()
+    }
+
+    pub fn each<F>(&self, mut callback: F)
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        self.engine.lock().each_raw(&mut |k, v| {
+            callback(k, v);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Forces every write so far to stable storage.
+    pub fn sync(&self) -> Result<()> {
+        self.syncer
+            .wait(self.syncer.written.load(Ordering::Acquire))
+    }
+
+    /// Writes a snapshot of the engine and truncates the log.
+    pub fn snapshot(&self) -> Result<()> {
+        self.write_snapshot(&mut self.wal())
+    }
+
+    // The snapshot is written beside the current one and renamed over it, then the log
+    // is truncated. A crash between the two replays the log over a snapshot that
+    // already contains it, which yields the same state.
+    fn write_snapshot(&self, wal: &mut Wal) -> Result<()> {
+        let tmp = self.dir.join(SNAPSHOT_TMP_FILE);
+        let file = File::create(&tmp)?;
+        let mut out = BufWriter::new(file);
+        out.write_all(SNAPSHOT_MAGIC)?;
+        let mut written = Ok(());
+        self.engine.lock().each_raw(&mut |k, v| {
+            written = out.write_all(&encode(&Record::Put(k, v)));
+            if written.is_ok() {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        });
+        written?;
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        File::open(&self.dir)?.sync_all()?;
+        wal.file.set_len(WAL_MAGIC.len() as u64)?;
+        wal.file.sync_data()?;
+        wal.len = WAL_MAGIC.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine() -> KVEngine {
+        let config = r#"{"path":"/dev/shm","size":1073741824}"#;
+        KVEngine::start_string("vsmap", config, None::<fn(&str, &str, &str)>).unwrap()
+    }
+
+    fn log(records: &[Record]) -> Vec<u8> {
+        let mut buf = WAL_MAGIC.to_vec();
+        for record in records {
+            buf.extend_from_slice(&encode(record));
+        }
+        buf
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let put = encode(&Record::Put(b"key", b"value"));
+        match decode(&put[8..]) {
+            Some(Record::Put(k, v)) => assert_eq!((k, v), (&b"key"[..], &b"value"[..])),
+            _ => panic!(),
+        }
+        let remove = encode(&Record::Remove(b"key"));
+        match decode(&remove[8..]) {
+            Some(Record::Remove(k)) => assert_eq!(k, b"key"),
+            _ => panic!(),
+        }
+        assert!(decode(&[TAG_PUT, 9, 0, 0, 0]).is_none());
+        assert!(decode(&[7, 0, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn torn_tail() {
+        let full = log(&[Record::Put(b"a", b"1"), Record::Put(b"b", b"2")]);
+        let valid = log(&[Record::Put(b"a", b"1")]).len();
+        for cut in valid..full.len() {
+            let mut engine = engine();
+            assert_eq!(replay(&mut engine, &full[..cut], WAL_MAGIC).unwrap(), valid);
+            assert_eq!(engine.count(), 1);
+        }
+        let mut engine = engine();
+        assert_eq!(replay(&mut engine, &full, WAL_MAGIC).unwrap(), full.len());
+        assert_eq!(engine.count(), 2);
+    }
+
+    #[test]
+    fn damaged_record() {
+        let mut buf = log(&[
+            Record::Put(b"a", b"1"),
+            Record::Put(b"b", b"2"),
+            Record::Remove(b"a"),
+        ]);
+        let valid = log(&[Record::Put(b"a", b"1")]).len();
+        // Flip a value byte of the second record: its CRC no longer matches, and
+        // nothing after it is applied.
+        buf[valid + 8 + 5 + 1] ^= 1;
+        let mut engine = engine();
+        assert_eq!(replay(&mut engine, &buf, WAL_MAGIC).unwrap(), valid);
+        assert_eq!(engine.get_copy_raw(b"a"), Some(b"1".to_vec()));
+        assert!(!engine.exists_raw(b"b"));
+    }
+
+    #[test]
+    fn remove_of_missing_key() {
+        let buf = log(&[Record::Remove(b"a"), Record::Put(b"b", b"2")]);
+        let mut engine = engine();
+        assert_eq!(replay(&mut engine, &buf, WAL_MAGIC).unwrap(), buf.len());
+        assert_eq!(engine.count(), 1);
+    }
+}